@@ -0,0 +1,21 @@
+//! Sends a message from a browser build.
+//!
+//! The compile test for `wasm32-unknown-unknown`:
+//!
+//! ```sh
+//! cargo check --target wasm32-unknown-unknown --example wasm
+//! ```
+//!
+//! In the browser, requests go out with the page's Google cookies, so the
+//! cookie values given here are not sent. See the readme for what is left
+//! out of browser builds.
+
+use gemini_chat_api::{AsyncChatbot, Model, Result};
+
+/// Connects with the default model and returns Gemini's reply to `message`.
+pub async fn ask(secure_1psid: &str, secure_1psidts: &str, message: &str) -> Result<String> {
+    let mut chatbot =
+        AsyncChatbot::new(secure_1psid, secure_1psidts, Model::default(), None, 30).await?;
+    let response = chatbot.ask(message, None).await?;
+    Ok(response.content)
+}
@@ -0,0 +1,145 @@
+//! Bounded history of recent requests for diagnostics.
+
+use crate::enums::Endpoint;
+use crate::error::ErrorCategory;
+use crate::platform::SystemTime;
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Metadata about a single request. Bodies are never recorded.
+#[derive(Debug, Clone)]
+pub struct ActivityRecord {
+    /// When the request was sent.
+    pub timestamp: SystemTime,
+    /// Endpoint the request went to.
+    pub endpoint: Endpoint,
+    /// HTTP status, or `None` if no response was received.
+    pub status: Option<u16>,
+    /// Time until the response headers arrived.
+    pub duration: Duration,
+    /// Request ID for chat requests.
+    pub reqid: Option<u32>,
+    /// Category of the error the operation failed with, if any.
+    pub error: Option<ErrorCategory>,
+}
+
+/// Ring buffer of the most recent [`ActivityRecord`]s.
+///
+/// With a capacity of 0 nothing is recorded and no lock is taken.
+#[derive(Debug, Default)]
+pub(crate) struct ActivityLog {
+    capacity: usize,
+    records: Mutex<VecDeque<ActivityRecord>>,
+}
+
+impl ActivityLog {
+    /// Creates a log keeping at most `capacity` records.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the maximum number of records kept.
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Appends a record, dropping the oldest one when full.
+    pub(crate) fn record(&self, record: ActivityRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Attaches an error to the newest record of a request that has none yet.
+    ///
+    /// Used for failures detected after the response arrived, such as parse
+    /// errors. Nothing happens if the newest matching record already has an
+    /// error or does not exist.
+    pub(crate) fn mark_failed(&self, endpoint: Endpoint, reqid: Option<u32>, error: ErrorCategory) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(record) = records
+            .iter_mut()
+            .rev()
+            .find(|r| r.endpoint == endpoint && r.reqid == reqid)
+        {
+            record.error.get_or_insert(error);
+        }
+    }
+
+    /// Returns the records, oldest first.
+    pub(crate) fn snapshot(&self) -> Vec<ActivityRecord> {
+        if self.capacity == 0 {
+            return Vec::new();
+        }
+        self.records
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(reqid: u32) -> ActivityRecord {
+        ActivityRecord {
+            timestamp: SystemTime::UNIX_EPOCH,
+            endpoint: Endpoint::Generate,
+            status: Some(200),
+            duration: Duration::from_millis(5),
+            reqid: Some(reqid),
+            error: None,
+        }
+    }
+
+    fn reqids(log: &ActivityLog) -> Vec<Option<u32>> {
+        log.snapshot().iter().map(|r| r.reqid).collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_records() {
+        let log = ActivityLog::new(3);
+        for reqid in 1..=5 {
+            log.record(record(reqid));
+        }
+        assert_eq!(reqids(&log), [Some(3), Some(4), Some(5)]);
+        assert_eq!(log.capacity(), 3);
+    }
+
+    #[test]
+    fn zero_capacity_records_nothing() {
+        let log = ActivityLog::new(0);
+        log.record(record(1));
+        log.mark_failed(Endpoint::Generate, Some(1), ErrorCategory::Parse);
+        assert!(log.snapshot().is_empty());
+    }
+
+    #[test]
+    fn mark_failed_updates_the_newest_match_once() {
+        let log = ActivityLog::new(4);
+        log.record(record(1));
+        log.record(record(1));
+        log.record(record(2));
+        log.mark_failed(Endpoint::Generate, Some(1), ErrorCategory::Parse);
+        log.mark_failed(Endpoint::Generate, Some(1), ErrorCategory::Network);
+        log.mark_failed(Endpoint::Upload, Some(2), ErrorCategory::Parse);
+        let errors: Vec<_> = log.snapshot().iter().map(|r| r.error).collect();
+        assert_eq!(errors, [None, Some(ErrorCategory::Parse), None]);
+    }
+}
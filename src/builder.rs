@@ -0,0 +1,234 @@
+//! Composition of messages that interleave text and images.
+
+use crate::client::{AsyncChatbot, ChatResponse};
+use crate::error::{Error, Result};
+use crate::platform;
+use crate::utils::{detect_image_mime, resolve_proxy, upload_file, EnvProxy};
+
+use reqwest::Client;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::{Path, PathBuf};
+
+/// A piece of a multimodal message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    /// Message text.
+    Text(String),
+    /// Image data held in memory.
+    ImageBytes(Vec<u8>),
+    /// Image file, read when the message is sent.
+    #[cfg(not(target_arch = "wasm32"))]
+    ImagePath(PathBuf),
+    /// Image URL, downloaded when the message is sent.
+    ImageUrl(String),
+}
+
+impl Part {
+    /// Returns true for the image variants.
+    pub fn is_image(&self) -> bool {
+        !matches!(self, Part::Text(_))
+    }
+}
+
+/// Builds a message from text and images in the order they are added.
+///
+/// Images are referred to in the message text by `[img1]`, `[img2]`, ...,
+/// numbered in the order they were added, and uploaded in the same order so
+/// that the placeholders match the attachments.
+///
+/// # Example
+/// ```no_run
+/// # async fn run(chatbot: &mut gemini_chat_api::AsyncChatbot) -> gemini_chat_api::Result<()> {
+/// use gemini_chat_api::MultimodalMessageBuilder;
+///
+/// let response = MultimodalMessageBuilder::new()
+///     .add_text("What's in this image?")
+///     .add_image_path("first.png".as_ref())
+///     .add_text("Compare to")
+///     .add_image_url("https://example.com/second.jpg")
+///     .add_text("Describe differences.")
+///     .ask(chatbot)
+///     .await?;
+/// println!("{}", response.content);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MultimodalMessageBuilder {
+    parts: Vec<Part>,
+}
+
+impl MultimodalMessageBuilder {
+    /// Creates an empty builder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends message text.
+    pub fn add_text(&mut self, text: &str) -> &mut Self {
+        self.parts.push(Part::Text(text.to_string()));
+        self
+    }
+
+    /// Appends an image held in memory.
+    pub fn add_image_bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.parts.push(Part::ImageBytes(data.to_vec()));
+        self
+    }
+
+    /// Appends an image file. The file is read when uploads are built.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn add_image_path(&mut self, path: &Path) -> &mut Self {
+        self.parts.push(Part::ImagePath(path.to_path_buf()));
+        self
+    }
+
+    /// Appends an image URL. The image is downloaded when uploads are built.
+    pub fn add_image_url(&mut self, url: &str) -> &mut Self {
+        self.parts.push(Part::ImageUrl(url.to_string()));
+        self
+    }
+
+    /// Returns the parts added so far.
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    /// Assembles the message text.
+    ///
+    /// Text parts are trimmed and joined with spaces; each image is replaced
+    /// by its `[imgN]` placeholder.
+    pub fn build_message(&self) -> String {
+        let mut image_index = 0;
+        let mut pieces = Vec::with_capacity(self.parts.len());
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => {
+                    let text = text.trim();
+                    if !text.is_empty() {
+                        pieces.push(text.to_string());
+                    }
+                }
+                _ => {
+                    image_index += 1;
+                    pieces.push(format!("[img{}]", image_index));
+                }
+            }
+        }
+        pieces.join(" ")
+    }
+
+    /// Uploads every image part, in order.
+    ///
+    /// # Arguments
+    /// * `proxy` - Optional proxy URL used for downloads and uploads; without
+    ///   one, the environment is used as described in [`resolve_proxy`]
+    ///
+    /// # Returns
+    /// The upload identifiers, one per image part.
+    ///
+    /// # Errors
+    /// Returns `Error::Upload` if an image cannot be read, downloaded, is not
+    /// a supported format, or fails to upload.
+    pub async fn build_uploads(&self, proxy: Option<&str>) -> Result<Vec<String>> {
+        let env = EnvProxy::from_env();
+        let images = self
+            .load_images(|url| resolve_proxy(proxy, Some(&env), url))
+            .await?;
+        let mut upload_ids = Vec::with_capacity(images.len());
+        for (_, data) in &images {
+            upload_ids.push(upload_file(data, proxy).await?);
+        }
+        Ok(upload_ids)
+    }
+
+    /// Uploads the images through `chatbot` and sends the assembled message.
+    ///
+    /// Uploads are paced and reported like those of
+    /// [`AsyncChatbot::upload_files`], with each image named by its path,
+    /// URL or position.
+    ///
+    /// # Errors
+    /// Returns `Error::Upload` if the message has no parts or an image
+    /// cannot be loaded, `Error::AttachmentUpload` if one fails to upload,
+    /// and otherwise the errors of [`AsyncChatbot::ask`].
+    pub async fn ask(&self, chatbot: &mut AsyncChatbot) -> Result<ChatResponse> {
+        if self.parts.is_empty() {
+            return Err(Error::Upload("multimodal message is empty".to_string()));
+        }
+        let images = self.load_images(|url| chatbot.proxy_for(url)).await?;
+        let files: Vec<(&str, &[u8])> = images
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let upload_ids = chatbot.upload_files(&files).await?;
+        chatbot
+            .ask_with_uploaded(&self.build_message(), &upload_ids)
+            .await
+    }
+
+    /// Reads or downloads the image parts and checks their format.
+    ///
+    /// `proxy_for` picks the proxy for each image URL. Returns each image
+    /// with a name for events and errors.
+    async fn load_images<P>(&self, proxy_for: P) -> Result<Vec<(String, Vec<u8>)>>
+    where
+        P: Fn(&str) -> Option<String>,
+    {
+        let mut images = Vec::new();
+        for part in &self.parts {
+            let (data, source) = match part {
+                Part::Text(_) => continue,
+                Part::ImageBytes(data) => (data.clone(), format!("image {}", images.len() + 1)),
+                #[cfg(not(target_arch = "wasm32"))]
+                Part::ImagePath(path) => {
+                    let data = tokio::fs::read(path).await.map_err(|e| {
+                        Error::Upload(format!("failed to read {}: {}", path.display(), e))
+                    })?;
+                    (data, path.display().to_string())
+                }
+                Part::ImageUrl(url) => {
+                    let client = download_client(proxy_for(url).as_deref())?;
+                    (download(&client, url).await?, url.clone())
+                }
+            };
+            if detect_image_mime(&data).is_none() {
+                return Err(Error::Upload(format!(
+                    "Unsupported image format: {}",
+                    source
+                )));
+            }
+            images.push((source, data));
+        }
+        Ok(images)
+    }
+}
+
+/// Creates the HTTP client used for image downloads, with a resolved proxy.
+fn download_client(proxy: Option<&str>) -> Result<Client> {
+    platform::with_proxy(Client::builder(), proxy)
+        .and_then(|builder| builder.build())
+        .map_err(|e| Error::Upload(e.to_string()))
+}
+
+/// Downloads an image from `url`.
+async fn download(client: &Client, url: &str) -> Result<Vec<u8>> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| Error::Upload(format!("failed to download {}: {}", url, e)))?;
+    if !response.status().is_success() {
+        return Err(Error::Upload(format!(
+            "failed to download {}: status {}",
+            url,
+            response.status()
+        )));
+    }
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| Error::Upload(format!("failed to download {}: {}", url, e)))?;
+    Ok(bytes.to_vec())
+}
@@ -0,0 +1,144 @@
+//! Deserializable client configuration.
+
+use crate::client::AsyncChatbotBuilder;
+use crate::enums::Model;
+
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Client settings that can be loaded from a configuration file.
+///
+/// Durations are given in whole seconds. Omitted fields keep the builder
+/// defaults, and unknown fields are rejected so typos surface early.
+/// Retries are not configurable because the client does not retry requests.
+///
+/// ```
+/// use gemini_chat_api::ClientConfig;
+///
+/// let config: ClientConfig = serde_json::from_str(
+///     r#"{ "model": "gemini-2.5-flash", "timeout_secs": 60, "min_request_interval_secs": 2 }"#,
+/// )
+/// .unwrap();
+/// assert_eq!(config.timeout_secs, Some(60));
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Model name, as returned by [`Model::name`].
+    pub model: Model,
+    /// Optional proxy URL.
+    pub proxy: Option<String>,
+    /// Whether to use the proxy environment variables; defaults to true.
+    pub proxy_from_env: Option<bool>,
+    /// Read timeout in seconds, or 0 for the model's default.
+    pub timeout_secs: Option<u64>,
+    /// Connection timeout in seconds.
+    pub connect_timeout_secs: Option<u64>,
+    /// Minimum time between requests in seconds.
+    pub min_request_interval_secs: Option<u64>,
+    /// Maximum random extra delay between requests in seconds.
+    pub request_jitter_secs: Option<u64>,
+    /// Age in seconds after which __Secure-1PSIDTS is rotated before a request.
+    pub psidts_refresh_interval_secs: Option<u64>,
+    /// System prompt sent with the first message of each conversation.
+    pub system_prompt: Option<String>,
+    /// Number of turns [`rewind`](crate::client::AsyncChatbot::rewind) can undo.
+    pub rewind_depth: Option<usize>,
+    /// Override for the upload `push-id` header.
+    pub upload_push_id: Option<String>,
+}
+
+impl ClientConfig {
+    /// Applies these settings to a builder.
+    pub(crate) fn apply(self, mut builder: AsyncChatbotBuilder) -> AsyncChatbotBuilder {
+        builder = builder.model(self.model).system_prompt(self.system_prompt);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy);
+        }
+        if let Some(enabled) = self.proxy_from_env {
+            builder = builder.proxy_from_env(enabled);
+        }
+        if let Some(secs) = self.timeout_secs {
+            builder = builder.timeout(secs);
+        }
+        if let Some(secs) = self.connect_timeout_secs {
+            builder = builder.with_connection_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.min_request_interval_secs {
+            builder = builder.min_request_interval(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.request_jitter_secs {
+            builder = builder.request_jitter(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.psidts_refresh_interval_secs {
+            builder = builder.psidts_refresh_interval(Duration::from_secs(secs));
+        }
+        if let Some(depth) = self.rewind_depth {
+            builder = builder.rewind_depth(depth);
+        }
+        if let Some(push_id) = &self.upload_push_id {
+            builder = builder.upload_push_id(push_id);
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = include_str!("../testdata/config/client.toml");
+    const JSON: &str = include_str!("../testdata/config/client.json");
+
+    fn assert_sample(config: &ClientConfig) {
+        assert_eq!(config.model, Model::G2_5Pro);
+        assert_eq!(config.proxy.as_deref(), Some("http://127.0.0.1:3128"));
+        assert_eq!(config.proxy_from_env, Some(false));
+        assert_eq!(config.timeout_secs, Some(90));
+        assert_eq!(config.connect_timeout_secs, Some(5));
+        assert_eq!(config.min_request_interval_secs, Some(2));
+        assert_eq!(config.request_jitter_secs, Some(1));
+        assert_eq!(config.psidts_refresh_interval_secs, Some(600));
+        assert_eq!(
+            config.system_prompt.as_deref(),
+            Some("Answer in one sentence.")
+        );
+        assert_eq!(config.rewind_depth, Some(4));
+        assert_eq!(
+            config.upload_push_id.as_deref(),
+            Some("feeds/custom-push-id")
+        );
+    }
+
+    #[test]
+    fn deserializes_toml_and_json() {
+        assert_sample(&toml::from_str(TOML).unwrap());
+        assert_sample(&serde_json::from_str(JSON).unwrap());
+    }
+
+    #[test]
+    fn omitted_fields_keep_defaults() {
+        let config: ClientConfig = toml::from_str("").unwrap();
+        assert_eq!(config.model, Model::Unspecified);
+        assert_eq!(config.timeout_secs, None);
+        assert_eq!(config.proxy_from_env, None);
+    }
+
+    #[test]
+    fn rejects_unknown_fields_and_models() {
+        let error = toml::from_str::<ClientConfig>("timeout = 30").unwrap_err();
+        assert!(
+            error.to_string().contains("unknown field `timeout`"),
+            "{}",
+            error
+        );
+        let error =
+            serde_json::from_str::<ClientConfig>(r#"{"modle": "gemini-2.5-pro"}"#).unwrap_err();
+        assert!(
+            error.to_string().contains("unknown field `modle`"),
+            "{}",
+            error
+        );
+        assert!(toml::from_str::<ClientConfig>(r#"model = "gemini-9""#).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! Passphrase encryption of saved conversations and cookies.
+//!
+//! Encrypted files start with [`ENCRYPTED_MAGIC`], followed by a 16-byte
+//! Argon2id salt, a 12-byte nonce and the ChaCha20-Poly1305 ciphertext of
+//! the plaintext file. The header is authenticated along with the contents.
+//! Encryption needs the `encryption` feature; encrypted files are recognized
+//! without it so that loading one fails clearly instead of as invalid JSON.
+
+#[cfg(any(feature = "encryption", not(target_arch = "wasm32")))]
+use crate::error::{Error, Result};
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+
+/// First bytes of every encrypted file, including the format version.
+pub const ENCRYPTED_MAGIC: &[u8; 8] = b"GEMCHAT\x01";
+
+#[cfg(feature = "encryption")]
+const SALT_LEN: usize = 16;
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+#[cfg(feature = "encryption")]
+const HEADER_LEN: usize = ENCRYPTED_MAGIC.len() + SALT_LEN + NONCE_LEN;
+/// Length of the Poly1305 authentication tag.
+#[cfg(feature = "encryption")]
+const TAG_LEN: usize = 16;
+
+/// Whether `data` is an encrypted file.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
+/// Encrypts `plaintext` with a key derived from `passphrase`.
+///
+/// A fresh salt and nonce are drawn for every call, so encrypting the same
+/// data twice gives different output.
+///
+/// # Errors
+/// Returns `Error::InvalidInput` if the passphrase is empty.
+///
+/// # Example
+/// ```
+/// use gemini_chat_api::crypto::{decrypt, encrypt, is_encrypted};
+///
+/// let data = encrypt(b"[]", "correct horse").unwrap();
+/// assert!(is_encrypted(&data));
+/// assert_eq!(decrypt(&data, "correct horse").unwrap(), b"[]");
+/// assert!(decrypt(&data, "wrong").is_err());
+/// ```
+#[cfg(feature = "encryption")]
+pub fn encrypt(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    use chacha20poly1305::aead::{Aead, Payload};
+    use rand::RngCore;
+
+    if passphrase.is_empty() {
+        return Err(Error::InvalidInput(
+            "encryption passphrase is empty".to_string(),
+        ));
+    }
+    let mut header = [0u8; HEADER_LEN];
+    header[..ENCRYPTED_MAGIC.len()].copy_from_slice(ENCRYPTED_MAGIC);
+    rand::thread_rng().fill_bytes(&mut header[ENCRYPTED_MAGIC.len()..]);
+    let (salt, nonce) = header[ENCRYPTED_MAGIC.len()..].split_at(SALT_LEN);
+
+    let ciphertext = cipher(passphrase, salt)?
+        .encrypt(
+            nonce.into(),
+            Payload {
+                msg: plaintext,
+                aad: &header,
+            },
+        )
+        .map_err(|_| Error::Encryption("encryption failed".to_string()))?;
+
+    let mut out = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypts data produced by [`encrypt`].
+///
+/// # Errors
+/// Returns `Error::Encryption` if `data` is not an encrypted file or is
+/// truncated, or if the passphrase is wrong or the data was modified; the
+/// last two cannot be told apart.
+#[cfg(feature = "encryption")]
+pub fn decrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    use chacha20poly1305::aead::{Aead, Payload};
+
+    if !is_encrypted(data) {
+        return Err(Error::Encryption("data is not encrypted".to_string()));
+    }
+    if data.len() < HEADER_LEN + TAG_LEN {
+        return Err(Error::Encryption(format!(
+            "encrypted data is truncated ({} bytes)",
+            data.len()
+        )));
+    }
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let (salt, nonce) = header[ENCRYPTED_MAGIC.len()..].split_at(SALT_LEN);
+
+    cipher(passphrase, salt)?
+        .decrypt(
+            nonce.into(),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| Error::Encryption("wrong passphrase, or the data is corrupted".to_string()))
+}
+
+/// Derives the cipher for `passphrase` and `salt` with Argon2id.
+#[cfg(feature = "encryption")]
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<chacha20poly1305::ChaCha20Poly1305> {
+    use chacha20poly1305::KeyInit;
+
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| Error::Encryption(format!("key derivation failed: {}", e)))?;
+    Ok(chacha20poly1305::ChaCha20Poly1305::new(&key.into()))
+}
+
+/// Reads a text file, decrypting it if it is encrypted.
+///
+/// Plaintext files are returned unchanged whether or not a passphrase is
+/// given.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn read_text(path: &Path, passphrase: Option<&str>) -> Result<String> {
+    decode_text(std::fs::read(path)?, path, passphrase)
+}
+
+/// Decodes the contents of a text file read from `path`, decrypting them if
+/// they are encrypted.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn decode_text(data: Vec<u8>, path: &Path, passphrase: Option<&str>) -> Result<String> {
+    if !is_encrypted(&data) {
+        return String::from_utf8(data)
+            .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)));
+    }
+    let Some(passphrase) = passphrase else {
+        return Err(Error::Encryption(format!(
+            "{} is encrypted and no passphrase was given",
+            path.display()
+        )));
+    };
+    #[cfg(feature = "encryption")]
+    {
+        String::from_utf8(decrypt(&data, passphrase)?)
+            .map_err(|_| Error::Encryption("decrypted data is not UTF-8".to_string()))
+    }
+    #[cfg(not(feature = "encryption"))]
+    {
+        let _ = passphrase;
+        Err(Error::Encryption(format!(
+            "{} is encrypted; enable the `encryption` feature to read it",
+            path.display()
+        )))
+    }
+}
+
+/// Encodes text for writing to a file, encrypted when a passphrase is given.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn encode_text(text: &str, passphrase: Option<&str>) -> Result<Vec<u8>> {
+    match passphrase {
+        #[cfg(feature = "encryption")]
+        Some(passphrase) => encrypt(text.as_bytes(), passphrase),
+        _ => Ok(text.as_bytes().to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempPath;
+
+    /// The message of an `Error::Encryption`.
+    fn encryption_error<T: std::fmt::Debug>(result: Result<T>) -> String {
+        match result {
+            Err(Error::Encryption(message)) => message,
+            other => panic!("expected Error::Encryption, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn round_trip_and_wrong_passphrase() {
+        let data = encrypt(b"[\"cookies\"]", "correct horse").unwrap();
+        assert!(is_encrypted(&data));
+        assert_eq!(data.len(), HEADER_LEN + 11 + TAG_LEN);
+        assert_eq!(decrypt(&data, "correct horse").unwrap(), b"[\"cookies\"]");
+        assert_ne!(encrypt(b"[\"cookies\"]", "correct horse").unwrap(), data);
+
+        let message = encryption_error(decrypt(&data, "battery staple"));
+        assert!(message.contains("wrong passphrase"), "{}", message);
+        assert!(matches!(encrypt(b"[]", ""), Err(Error::InvalidInput(_))));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn truncated_data() {
+        let data = encrypt(b"", "correct horse").unwrap();
+        // An empty plaintext leaves only the header and the tag
+        assert_eq!(data.len(), HEADER_LEN + TAG_LEN);
+        assert_eq!(decrypt(&data, "correct horse").unwrap(), b"");
+
+        let message = encryption_error(decrypt(&data[..HEADER_LEN + TAG_LEN - 1], "correct horse"));
+        assert!(message.contains("truncated"), "{}", message);
+        let message = encryption_error(decrypt(&data[..ENCRYPTED_MAGIC.len()], "correct horse"));
+        assert!(message.contains("truncated"), "{}", message);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn tampered_header() {
+        let data = encrypt(b"[]", "correct horse").unwrap();
+
+        // The salt and nonce are authenticated with the contents
+        for index in [ENCRYPTED_MAGIC.len(), HEADER_LEN - 1] {
+            let mut tampered = data.clone();
+            tampered[index] ^= 1;
+            let message = encryption_error(decrypt(&tampered, "correct horse"));
+            assert!(message.contains("corrupted"), "{}", message);
+        }
+
+        // Another format version is not recognized as encrypted
+        let mut tampered = data.clone();
+        tampered[ENCRYPTED_MAGIC.len() - 1] = 2;
+        assert!(!is_encrypted(&tampered));
+        let message = encryption_error(decrypt(&tampered, "correct horse"));
+        assert!(message.contains("not encrypted"), "{}", message);
+    }
+
+    #[test]
+    fn read_text_passes_plaintext_through() {
+        let file = TempPath::new("cookies.json");
+        std::fs::write(file.path(), "[]").unwrap();
+
+        assert_eq!(read_text(file.path(), Some("correct horse")).unwrap(), "[]");
+        assert_eq!(read_text(file.path(), None).unwrap(), "[]");
+    }
+
+    #[test]
+    fn read_text_recognizes_encrypted_files() {
+        let file = TempPath::new("cookies.json");
+        std::fs::write(file.path(), ENCRYPTED_MAGIC).unwrap();
+
+        let message = encryption_error(read_text(file.path(), None));
+        assert!(message.contains("no passphrase"), "{}", message);
+        let message = encryption_error(read_text(file.path(), Some("correct horse")));
+        #[cfg(feature = "encryption")]
+        assert!(message.contains("truncated"), "{}", message);
+        #[cfg(not(feature = "encryption"))]
+        assert!(message.contains("`encryption` feature"), "{}", message);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn read_text_decrypts_with_the_passphrase() {
+        let file = TempPath::new("cookies.json");
+        std::fs::write(
+            file.path(),
+            encode_text("[]", Some("correct horse")).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(read_text(file.path(), Some("correct horse")).unwrap(), "[]");
+        let message = encryption_error(read_text(file.path(), Some("battery staple")));
+        assert!(message.contains("wrong passphrase"), "{}", message);
+        let message = encryption_error(read_text(file.path(), None));
+        assert!(message.contains("no passphrase"), "{}", message);
+    }
+}
@@ -1,12 +1,14 @@
 //! Enums and constants for Gemini API endpoints, headers, and models.
 
+use crate::error::{Error, Result};
+
 use reqwest::header::{
     HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_LANGUAGE, CONTENT_TYPE, ORIGIN, REFERER,
     USER_AGENT,
 };
 
 /// API endpoints for Google Gemini.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Endpoint {
     /// Initialize session and get SNlM0e token.
     Init,
@@ -16,6 +18,8 @@ pub enum Endpoint {
     RotateCookies,
     /// Upload files/images.
     Upload,
+    /// Read and manage conversations through batchexecute RPCs.
+    BatchExecute,
 }
 
 impl Endpoint {
@@ -26,10 +30,22 @@ impl Endpoint {
             Endpoint::Generate => "https://gemini.google.com/_/BardChatUi/data/assistant.lamda.BardFrontendService/StreamGenerate",
             Endpoint::RotateCookies => "https://accounts.google.com/RotateCookies",
             Endpoint::Upload => "https://content-push.googleapis.com/upload",
+            Endpoint::BatchExecute => "https://gemini.google.com/_/BardChatUi/data/batchexecute",
         }
     }
 }
 
+/// User feedback on a response, mirroring the web UI controls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeedbackType {
+    /// Thumbs up.
+    ThumbsUp,
+    /// Thumbs down.
+    ThumbsDown,
+    /// Report the response with a reason.
+    Report(String),
+}
+
 /// Get headers for Gemini chat requests.
 pub fn gemini_headers() -> HeaderMap {
     let mut headers = HeaderMap::new();
@@ -94,18 +110,68 @@ pub fn rotate_cookies_headers() -> HeaderMap {
     headers
 }
 
+/// Default `push-id` header value sent with file uploads.
+pub const DEFAULT_UPLOAD_PUSH_ID: &str = "feeds/mcudyrk2a4khkz";
+
 /// Get headers for file upload requests.
 pub fn upload_headers() -> HeaderMap {
     let mut headers = HeaderMap::new();
     headers.insert(
         HeaderName::from_static("push-id"),
-        HeaderValue::from_static("feeds/mcudyrk2a4khkz"),
+        HeaderValue::from_static(DEFAULT_UPLOAD_PUSH_ID),
     );
     headers
 }
 
+/// Get headers for file upload requests with a custom `push-id`.
+///
+/// Returns `None` if `push_id` is not a valid header value.
+pub fn upload_headers_with_push_id(push_id: &str) -> Option<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        HeaderName::from_static("push-id"),
+        HeaderValue::from_str(push_id).ok()?,
+    );
+    Some(headers)
+}
+
+/// ID of the `x-goog-ext-525001261-jspb` header, which selects the model.
+///
+/// Set through the [`Model`] rather than [`ext_header`]; passing this ID to
+/// `ext_header` is rejected so the two cannot collide.
+pub const MODEL_EXT_HEADER_ID: u64 = 525_001_261;
+
+/// Builds an `x-goog-ext-{id}-jspb` header.
+///
+/// The web app sends several of these headers, each carrying a JSPB
+/// (JSON-encoded protobuf) array that toggles a feature. Their meaning is
+/// undocumented; this allows experimenting with newly discovered ones.
+///
+/// # Errors
+/// Returns `Error::InvalidInput` if `jspb` is not a valid header value, or
+/// if `id` is [`MODEL_EXT_HEADER_ID`].
+pub fn ext_header(id: u64, jspb: &str) -> Result<(HeaderName, HeaderValue)> {
+    if id == MODEL_EXT_HEADER_ID {
+        return Err(Error::InvalidInput(
+            "the model header is set by the model, not ext_header".to_string(),
+        ));
+    }
+    let name = HeaderName::try_from(format!("x-goog-ext-{}-jspb", id))
+        .map_err(|_| Error::InvalidInput(format!("invalid ext header id: {}", id)))?;
+    let value = HeaderValue::from_str(jspb)
+        .map_err(|_| Error::InvalidInput(format!("invalid value for header {}", name)))?;
+    Ok((name, value))
+}
+
+/// Static capability hints for a model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelCapabilities {
+    /// Approximate maximum prompt size in tokens accepted by the web app.
+    pub max_input_hint: usize,
+}
+
 /// Available Gemini model configurations.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub enum Model {
     /// Unspecified model - uses default.
     #[default]
@@ -131,6 +197,22 @@ pub enum Model {
 }
 
 impl Model {
+    /// All known model variants.
+    pub fn all() -> &'static [Model] {
+        &[
+            Model::Unspecified,
+            Model::G2_0Flash,
+            Model::G2_0FlashThinking,
+            Model::G2_5Flash,
+            Model::G2_5Pro,
+            Model::G2_0ExpAdvanced,
+            Model::G2_5ExpAdvanced,
+            Model::G3_0Pro,
+            Model::G3_0Flash,
+            Model::G3_0Thinking,
+        ]
+    }
+
     /// Get the model name string.
     pub fn name(&self) -> &'static str {
         match self {
@@ -147,10 +229,12 @@ impl Model {
         }
     }
 
-    /// Get model-specific headers (for x-goog-ext-525001261-jspb header).
-    pub fn headers(&self) -> Option<HeaderMap> {
-        let header_value = match self {
-            Model::Unspecified => return None,
+    /// Get the raw `x-goog-ext-525001261-jspb` header value for this model.
+    ///
+    /// Returns an empty string for [`Model::Unspecified`], which sends no header.
+    pub fn get_model_jspb_header(&self) -> &'static str {
+        match self {
+            Model::Unspecified => "",
             Model::G2_0Flash => r#"[1,null,null,null,"f299729663a2343f"]"#,
             Model::G2_0FlashThinking => r#"[null,null,null,null,"7ca48d02d802f20a"]"#,
             Model::G2_5Flash => r#"[1,null,null,null,"35609594dbe934d8"]"#,
@@ -166,22 +250,113 @@ impl Model {
             Model::G3_0Flash => {
                 r#"[1,null,null,null,"56fdd199312815e2",null,null,0,[4],null,null,2]"#
             }
-        };
+        }
+    }
+
+    /// Checks that this model's header value is a well-formed JSON array.
+    ///
+    /// # Errors
+    /// Returns `Error::Parse` naming the model if the value is malformed.
+    pub fn validate_model_headers(&self) -> Result<()> {
+        let value = self.get_model_jspb_header();
+        if value.is_empty() {
+            return Ok(());
+        }
+        match serde_json::from_str::<serde_json::Value>(value) {
+            Ok(serde_json::Value::Array(_)) => Ok(()),
+            _ => Err(Error::Parse(format!(
+                "Malformed x-goog-ext-525001261-jspb header for model {}: {}",
+                self.name(),
+                value
+            ))),
+        }
+    }
+
+    /// Get model-specific headers (for x-goog-ext-525001261-jspb header).
+    pub fn headers(&self) -> Option<HeaderMap> {
+        let header_value = self.get_model_jspb_header();
+        if header_value.is_empty() {
+            return None;
+        }
 
         let mut headers = HeaderMap::new();
         headers.insert(
             HeaderName::from_static("x-goog-ext-525001261-jspb"),
-            HeaderValue::from_str(header_value).unwrap(),
+            HeaderValue::from_str(header_value).ok()?,
         );
         Some(headers)
     }
 
+    /// Get the capability hints for this model.
+    ///
+    /// These are conservative approximations of what the web app accepts,
+    /// not documented limits.
+    pub fn capabilities(&self) -> ModelCapabilities {
+        let max_input_hint = match self {
+            Model::Unspecified | Model::G2_0Flash | Model::G2_0FlashThinking => 32_000,
+            Model::G2_0ExpAdvanced
+            | Model::G2_5Flash
+            | Model::G2_5Pro
+            | Model::G2_5ExpAdvanced
+            | Model::G3_0Pro
+            | Model::G3_0Flash
+            | Model::G3_0Thinking => 1_000_000,
+        };
+        ModelCapabilities { max_input_hint }
+    }
+
+    /// Get a reasonable request timeout in seconds for this model.
+    ///
+    /// Larger and thinking models take noticeably longer to answer.
+    pub fn default_timeout_secs(&self) -> u64 {
+        match self {
+            Model::Unspecified | Model::G2_0Flash => 30,
+            Model::G2_5Flash | Model::G3_0Flash => 45,
+            Model::G2_0FlashThinking | Model::G3_0Thinking => 60,
+            Model::G2_5Pro | Model::G3_0Pro => 120,
+            Model::G2_0ExpAdvanced | Model::G2_5ExpAdvanced => 180,
+        }
+    }
+
+    /// Whether this model typically answers within 30 seconds.
+    pub fn is_fast(&self) -> bool {
+        self.default_timeout_secs() <= 30
+    }
+
+    /// Whether this model exposes thinking traces.
+    pub fn is_thinking(&self) -> bool {
+        matches!(self, Model::G2_0FlashThinking | Model::G3_0Thinking)
+    }
+
     /// Whether this model requires advanced subscription.
     pub fn is_advanced_only(&self) -> bool {
         matches!(self, Model::G2_0ExpAdvanced | Model::G2_5ExpAdvanced)
     }
 
+    /// Whether this variant is a placeholder without a known header value.
+    ///
+    /// Placeholder variants may be added ahead of a model's release so that
+    /// downstream code compiles; requests sent with them are undefined. Every
+    /// current variant, including the Gemini 3.0 models, has a known header
+    /// value, so this returns `false` for all of them.
+    pub fn is_placeholder(&self) -> bool {
+        match self {
+            Model::Unspecified
+            | Model::G2_0Flash
+            | Model::G2_0FlashThinking
+            | Model::G2_5Flash
+            | Model::G2_5Pro
+            | Model::G2_0ExpAdvanced
+            | Model::G2_5ExpAdvanced
+            | Model::G3_0Pro
+            | Model::G3_0Flash
+            | Model::G3_0Thinking => false,
+        }
+    }
+
     /// Create model from name string.
+    ///
+    /// Accepts every string returned by [`Model::name`].
     pub fn from_name(name: &str) -> Option<Self> {
         match name {
             "unspecified" => Some(Model::Unspecified),
@@ -193,8 +368,48 @@ impl Model {
             "gemini-2.5-exp-advanced" => Some(Model::G2_5ExpAdvanced),
             "gemini-3.0-pro" => Some(Model::G3_0Pro),
             "gemini-3.0-flash" => Some(Model::G3_0Flash),
-            "gemini-3.0-thinking" => Some(Model::G3_0Thinking),
+            "gemini-3.0-thinking" | "gemini-3.0-flash-thinking" => Some(Model::G3_0Thinking),
+            _ => None,
+        }
+    }
+
+    /// Create model from the display name a reply reports, e.g. `"2.5 Pro"`.
+    ///
+    /// Matching ignores case and a leading `Gemini`, and `3` stands for `3.0`.
+    /// Names accepted by [`Model::from_name`] are also recognized.
+    pub fn from_display_name(label: &str) -> Option<Self> {
+        let label = label.trim().to_lowercase();
+        if let Some(model) = Model::from_name(&label) {
+            return Some(model);
+        }
+        let label = label.strip_prefix("gemini ").unwrap_or(&label);
+        let label = match label.strip_prefix("3 ") {
+            Some(rest) => format!("3.0 {}", rest),
+            None => label.to_string(),
+        };
+        match label.as_str() {
+            "2.0 flash" => Some(Model::G2_0Flash),
+            "2.0 flash thinking" => Some(Model::G2_0FlashThinking),
+            "2.5 flash" => Some(Model::G2_5Flash),
+            "2.5 pro" => Some(Model::G2_5Pro),
+            "2.0 experimental advanced" => Some(Model::G2_0ExpAdvanced),
+            "2.5 experimental advanced" => Some(Model::G2_5ExpAdvanced),
+            "3.0 pro" => Some(Model::G3_0Pro),
+            "3.0 flash" => Some(Model::G3_0Flash),
+            "3.0 flash thinking" | "3.0 thinking" => Some(Model::G3_0Thinking),
             _ => None,
         }
     }
 }
+
+impl<'de> serde::Deserialize<'de> for Model {
+    /// Deserializes a model from its name string, as accepted by [`Model::from_name`].
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        Model::from_name(&name)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown model: {}", name)))
+    }
+}
@@ -1,7 +1,15 @@
 //! Error types for the Gemini Chat API client.
 
+use crate::enums::Model;
+
 use thiserror::Error;
 
+const SESSION_EXPIRED_ADVICE: &str =
+    "Your Google session expired. Re-export your cookies from the browser.";
+
+const RATE_LIMITED_ADVICE: &str =
+    "Too many requests were sent. Wait a few minutes before trying again.";
+
 /// Main error type for the Gemini client.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -33,14 +41,500 @@ pub enum Error {
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
 
-    /// Client not initialized properly.
+    /// Operation is not available in the client's current state.
+    ///
+    /// A missing SNlM0e token no longer produces this error; `ask` re-fetches
+    /// it instead. It is still returned by endpoints that are not yet
+    /// supported, such as `submit_feedback`.
     #[error("Client not initialized: {0}")]
     NotInitialized(String),
 
+    /// Prompt is estimated to exceed the model's input limit.
+    #[error("Prompt too long: ~{estimated} tokens exceeds the ~{limit} token limit")]
+    PromptTooLong {
+        /// Estimated prompt size in tokens.
+        estimated: usize,
+        /// Model input limit hint in tokens.
+        limit: usize,
+    },
+
+    /// The server rejected the request body as too large (HTTP 413).
+    #[error("Request too large: {body_bytes} byte body was rejected")]
+    RequestTooLarge {
+        /// Size of the form-encoded body before compression.
+        body_bytes: usize,
+    },
+
+    /// The model requires Gemini Advanced, which the account does not have.
+    #[error("Model {} requires a Gemini Advanced subscription", .model.name())]
+    AdvancedRequired {
+        /// The requested model.
+        model: Model,
+    },
+
+    /// The account's usage quota for the model is exhausted (HTTP 429).
+    ///
+    /// Triggers the model fallback chain when one is configured.
+    #[error("Quota exceeded for model {}", .model.name())]
+    QuotaExceeded {
+        /// The model whose quota ran out.
+        model: Model,
+    },
+
+    /// Gemini answered with its generic temporary error.
+    ///
+    /// Retried automatically, see `AsyncChatbotBuilder::transient_retries`.
+    #[error("Gemini returned a temporary error (code {code})")]
+    Transient {
+        /// Error code from the response frame.
+        code: u64,
+    },
+
+    /// The Workspace administrator has turned Gemini off for this account.
+    #[error("Gemini has been disabled by your Workspace administrator")]
+    GeminiDisabledByAdmin,
+
+    /// The Workspace or education tenant does not support the Gemini web app.
+    #[error("Gemini is not available for this Workspace or education account")]
+    WorkspaceUnsupportedTenant,
+
+    /// An argument was rejected before sending anything.
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+
+    /// There is no previous turn to edit or rewind.
+    #[error("No previous turn in this conversation")]
+    NoPreviousTurn,
+
+    /// Gemini replied with a well-formed response without any text.
+    ///
+    /// Usually a refusal. Disabled with `allow_empty_responses`.
+    #[error("Gemini returned an empty response")]
+    EmptyResponse,
+
+    /// Gemini declined to process the prompt.
+    ///
+    /// Reported even with `allow_empty_responses`, since retrying the same
+    /// prompt will not help.
+    #[error("Gemini declined to answer this prompt")]
+    PromptBlocked,
+
+    /// An encrypted file could not be read or written.
+    ///
+    /// Covers a wrong or missing passphrase and truncated or modified files.
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    /// A shared conversation link points to nothing readable.
+    ///
+    /// The link is valid but the share was revoked, made private or never
+    /// existed.
+    #[error("Shared conversation {0} is not available")]
+    ShareUnavailable(String),
+
     /// File upload failed.
     #[error("Upload failed: {0}")]
     Upload(String),
+
+    /// One of several attachments failed to upload.
+    ///
+    /// The attachments before it were uploaded; their identifiers can be
+    /// reused to resume without uploading them again.
+    #[error("Upload of attachment {index} ({name}) failed: {source}")]
+    AttachmentUpload {
+        /// Position of the failed attachment.
+        index: usize,
+        /// Name of the failed attachment.
+        name: String,
+        /// Identifiers of the attachments uploaded before it, in order.
+        uploaded: Vec<String>,
+        /// Why the upload failed.
+        source: Box<Error>,
+    },
+}
+
+/// Coarse error category for logging and metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorCategory {
+    /// Invalid or expired credentials.
+    Authentication,
+    /// Account cannot use Gemini (e.g. disabled by a Workspace admin).
+    Account,
+    /// Transport-level failure.
+    Network,
+    /// Unexpected response format.
+    Parse,
+    /// Request timed out.
+    Timeout,
+    /// Cookie loading or validation failure.
+    Cookie,
+    /// Local file I/O failure.
+    Io,
+    /// JSON serialization/deserialization failure.
+    Json,
+    /// Client used in an invalid state.
+    State,
+    /// Request rejected before sending because of its input.
+    InvalidInput,
+    /// File upload failure.
+    Upload,
+    /// Encrypted file failure.
+    Encryption,
+    /// Requested content does not exist or is not shared.
+    Unavailable,
+}
+
+impl Error {
+    /// Returns the coarse category of this error.
+    pub fn error_category(&self) -> ErrorCategory {
+        match self {
+            Error::Authentication(_) => ErrorCategory::Authentication,
+            Error::GeminiDisabledByAdmin
+            | Error::WorkspaceUnsupportedTenant
+            | Error::AdvancedRequired { .. }
+            | Error::QuotaExceeded { .. } => ErrorCategory::Account,
+            Error::Network(_) | Error::Transient { .. } => ErrorCategory::Network,
+            Error::Parse(_) | Error::EmptyResponse => ErrorCategory::Parse,
+            Error::Timeout => ErrorCategory::Timeout,
+            Error::Cookie(_) => ErrorCategory::Cookie,
+            Error::Io(_) => ErrorCategory::Io,
+            Error::Json(_) => ErrorCategory::Json,
+            Error::NotInitialized(_) | Error::NoPreviousTurn => ErrorCategory::State,
+            Error::PromptTooLong { .. }
+            | Error::RequestTooLarge { .. }
+            | Error::InvalidInput(_)
+            | Error::PromptBlocked => ErrorCategory::InvalidInput,
+            Error::ShareUnavailable(_) => ErrorCategory::Unavailable,
+            Error::Upload(_) | Error::AttachmentUpload { .. } => ErrorCategory::Upload,
+            Error::Encryption(_) => ErrorCategory::Encryption,
+        }
+    }
+
+    /// Returns user-facing guidance on how to resolve this error.
+    ///
+    /// Meant for end users rather than developers, e.g. turning a missing
+    /// SNlM0e token into "your session expired". Sub-cases are recognized from
+    /// the HTTP status or message where a variant covers several causes.
+    /// Errors without an action the user can take, such as programming
+    /// errors, return `None`. The match has no catch-all arm, so a new variant
+    /// does not compile until its advice is decided.
+    pub fn advice(&self) -> Option<&'static str> {
+        match self {
+            Error::Authentication(message) if message.contains("consent") => Some(
+                "Google needs you to accept a consent screen. Open gemini.google.com in your \
+                 browser, accept it, then re-export your cookies.",
+            ),
+            Error::Authentication(_) => Some(SESSION_EXPIRED_ADVICE),
+            Error::Parse(message) if message.contains("Rate limit") => Some(RATE_LIMITED_ADVICE),
+            Error::Parse(message) if message.contains("SNlM0e") => Some(SESSION_EXPIRED_ADVICE),
+            Error::Parse(_) => Some(
+                "Gemini returned an unexpected response. Try again later; if it persists, \
+                 the client may need an update.",
+            ),
+            Error::Network(e) => match e.status().map(|s| s.as_u16()) {
+                Some(429) => Some(RATE_LIMITED_ADVICE),
+                Some(401) | Some(403) => Some(SESSION_EXPIRED_ADVICE),
+                Some(413) => Some("The request is too large. Use a smaller file or message."),
+                Some(status) if status >= 500 => {
+                    Some("Gemini is having problems. Try again in a few minutes.")
+                }
+                _ => Some("Could not reach Gemini. Check your internet connection or proxy."),
+            },
+            Error::Transient { .. } => Some("Gemini had a temporary problem. Try again."),
+            Error::EmptyResponse => Some("Gemini did not answer this message. Try rephrasing it."),
+            Error::PromptBlocked => {
+                Some("Gemini will not answer this message. Rephrase it or ask something else.")
+            }
+            Error::Timeout => Some("Gemini took too long to respond. Try again."),
+            Error::Cookie(_) => Some(
+                "Your cookie file is missing or invalid. Export __Secure-1PSID and \
+                 __Secure-1PSIDTS from gemini.google.com again.",
+            ),
+            Error::PromptTooLong { .. } => {
+                Some("Your message is too long for this model. Shorten it or split it up.")
+            }
+            Error::RequestTooLarge { .. } => {
+                Some("Your message is too large to send. Split it into smaller parts.")
+            }
+            Error::AdvancedRequired { .. } => Some(
+                "This model needs a Gemini Advanced subscription. Choose another model \
+                 or upgrade your account.",
+            ),
+            Error::QuotaExceeded { .. } => Some(
+                "You have reached the usage limit for this model. Try again later or \
+                 choose another model.",
+            ),
+            Error::GeminiDisabledByAdmin => Some(
+                "Your Workspace administrator has turned Gemini off. Ask them to enable it, \
+                 or use a personal Google account.",
+            ),
+            Error::WorkspaceUnsupportedTenant => Some(
+                "Gemini is not available for this organization's accounts. \
+                 Use a personal Google account.",
+            ),
+            Error::Upload(message) if message.contains("413") || message.contains("too large") => {
+                Some("The file is too big to upload. Use a smaller or compressed file.")
+            }
+            Error::Upload(message) if message.contains("Unsupported image format") => {
+                Some("This image format is not supported. Use PNG, JPEG, GIF or WebP.")
+            }
+            Error::Upload(_) => Some("The file could not be uploaded. Try again later."),
+            Error::AttachmentUpload { source, .. } => source.advice(),
+            Error::Io(_) => {
+                Some("A local file could not be read or written. Check the path and permissions.")
+            }
+            Error::Encryption(message) if message.contains("truncated") => {
+                Some("The encrypted file is incomplete. Restore it from a backup.")
+            }
+            Error::Encryption(_) => Some(
+                "The encrypted file could not be opened. Check the passphrase; the file \
+                 may also be damaged.",
+            ),
+            Error::NoPreviousTurn => Some("There is no previous message to change yet."),
+            Error::ShareUnavailable(_) => {
+                Some("This shared conversation was removed or is private. Ask for a new link.")
+            }
+            // Programming or unsupported-feature errors; nothing for a user to do
+            Error::Json(_) | Error::NotInitialized(_) | Error::InvalidInput(_) => None,
+        }
+    }
+
+    /// Whether this is a cookie loading error.
+    #[inline]
+    pub fn is_cookie_error(&self) -> bool {
+        matches!(self, Error::Cookie(_))
+    }
+
+    /// Whether this is a JSON serialization error.
+    #[inline]
+    pub fn is_json_error(&self) -> bool {
+        matches!(self, Error::Json(_))
+    }
+
+    /// Whether this is a file I/O error.
+    #[inline]
+    pub fn is_io_error(&self) -> bool {
+        matches!(self, Error::Io(_))
+    }
+
+    /// Whether this is a file upload error.
+    #[inline]
+    pub fn is_upload_error(&self) -> bool {
+        matches!(self, Error::Upload(_) | Error::AttachmentUpload { .. })
+    }
+
+    /// Whether this is a request timeout.
+    ///
+    /// Includes transport timeouts, which arrive as `Error::Network`, and
+    /// the cause of a failed attachment upload.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Timeout => true,
+            Error::Network(e) => e.is_timeout(),
+            Error::AttachmentUpload { source, .. } => source.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// Whether this is a network error, including the cause of a failed
+    /// attachment upload.
+    pub fn is_network_error(&self) -> bool {
+        match self {
+            Error::Network(_) => true,
+            Error::AttachmentUpload { source, .. } => source.is_network_error(),
+            _ => false,
+        }
+    }
+
+    /// Whether the client was not initialized properly.
+    #[inline]
+    pub fn is_not_initialized(&self) -> bool {
+        matches!(self, Error::NotInitialized(_))
+    }
 }
 
 /// Result type alias for Gemini operations.
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Variants whose errors are for developers, so they have no advice.
+    const EXEMPT: [&str; 3] = ["Json", "NotInitialized", "InvalidInput"];
+
+    /// Names the variant of `error`. The match has no catch-all arm, so a new
+    /// variant does not compile until it is added here and to [`samples`].
+    fn variant_name(error: &Error) -> &'static str {
+        match error {
+            Error::Authentication(_) => "Authentication",
+            Error::Network(_) => "Network",
+            Error::Parse(_) => "Parse",
+            Error::Timeout => "Timeout",
+            Error::Cookie(_) => "Cookie",
+            Error::Io(_) => "Io",
+            Error::Json(_) => "Json",
+            Error::NotInitialized(_) => "NotInitialized",
+            Error::PromptTooLong { .. } => "PromptTooLong",
+            Error::RequestTooLarge { .. } => "RequestTooLarge",
+            Error::AdvancedRequired { .. } => "AdvancedRequired",
+            Error::QuotaExceeded { .. } => "QuotaExceeded",
+            Error::Transient { .. } => "Transient",
+            Error::GeminiDisabledByAdmin => "GeminiDisabledByAdmin",
+            Error::WorkspaceUnsupportedTenant => "WorkspaceUnsupportedTenant",
+            Error::InvalidInput(_) => "InvalidInput",
+            Error::NoPreviousTurn => "NoPreviousTurn",
+            Error::EmptyResponse => "EmptyResponse",
+            Error::PromptBlocked => "PromptBlocked",
+            Error::Encryption(_) => "Encryption",
+            Error::ShareUnavailable(_) => "ShareUnavailable",
+            Error::Upload(_) => "Upload",
+            Error::AttachmentUpload { .. } => "AttachmentUpload",
+        }
+    }
+
+    /// One error of every variant, in declaration order.
+    fn samples() -> Vec<Error> {
+        let network = reqwest::Client::new().get("not a url").build().unwrap_err();
+        let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        vec![
+            Error::Authentication("cookies expired".to_string()),
+            Error::Network(network),
+            Error::Parse("bad frame".to_string()),
+            Error::Timeout,
+            Error::Cookie("__Secure-1PSID is empty".to_string()),
+            Error::Io(std::io::Error::other("disk full")),
+            Error::Json(json),
+            Error::NotInitialized("feedback endpoint not available".to_string()),
+            Error::PromptTooLong {
+                estimated: 40_000,
+                limit: 32_000,
+            },
+            Error::RequestTooLarge { body_bytes: 1 },
+            Error::AdvancedRequired {
+                model: Model::G2_5ExpAdvanced,
+            },
+            Error::QuotaExceeded {
+                model: Model::G2_5Pro,
+            },
+            Error::Transient { code: 1013 },
+            Error::GeminiDisabledByAdmin,
+            Error::WorkspaceUnsupportedTenant,
+            Error::InvalidInput("chunk_size must be greater than zero".to_string()),
+            Error::NoPreviousTurn,
+            Error::EmptyResponse,
+            Error::PromptBlocked,
+            Error::Encryption("wrong passphrase".to_string()),
+            Error::ShareUnavailable("abc123".to_string()),
+            Error::Upload("Upload failed with status: 500".to_string()),
+            Error::AttachmentUpload {
+                index: 1,
+                name: "b.png".to_string(),
+                uploaded: vec!["/contrib_service/ttl_1d/a".to_string()],
+                source: Box::new(Error::Upload("Unsupported image format".to_string())),
+            },
+        ]
+    }
+
+    #[test]
+    fn every_variant_has_advice_or_is_exempt() {
+        let samples = samples();
+        let mut names: Vec<&str> = samples.iter().map(variant_name).collect();
+        names.dedup();
+        assert_eq!(names.len(), samples.len(), "one sample per variant");
+        assert_eq!(names.len(), 23, "a variant is missing from samples()");
+
+        for error in &samples {
+            let name = variant_name(error);
+            match error.advice() {
+                Some(advice) => {
+                    assert!(!EXEMPT.contains(&name), "{} is exempt but has advice", name);
+                    assert!(advice.ends_with('.'), "{}: {}", name, advice);
+                }
+                None => assert!(EXEMPT.contains(&name), "{} has no advice", name),
+            }
+        }
+    }
+
+    #[test]
+    fn advice_distinguishes_sub_cases() {
+        let advice = |error: Error| error.advice().unwrap();
+        assert!(advice(Error::Authentication("consent required".to_string())).contains("consent"));
+        assert_eq!(
+            advice(Error::Authentication("redirected to login".to_string())),
+            SESSION_EXPIRED_ADVICE
+        );
+        assert_eq!(
+            advice(Error::Parse(
+                "SNlM0e not found. Rate limit likely exceeded.".to_string()
+            )),
+            RATE_LIMITED_ADVICE
+        );
+        assert_eq!(
+            advice(Error::Parse(
+                "SNlM0e value not found in response.".to_string()
+            )),
+            SESSION_EXPIRED_ADVICE
+        );
+        assert!(advice(Error::Upload("status: 413".to_string())).contains("too big"));
+        assert!(advice(Error::Encryption("data is truncated".to_string())).contains("incomplete"));
+        assert!(advice(samples().pop().unwrap()).contains("not supported"));
+    }
+
+    /// A transport error from a server that accepts connections but never
+    /// answers, with the client giving up after 50 ms.
+    async fn timed_out_request() -> reqwest::Error {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let _accept = tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        reqwest::Client::builder()
+            .timeout(std::time::Duration::from_millis(50))
+            .no_proxy()
+            .build()
+            .unwrap()
+            .get(url)
+            .send()
+            .await
+            .unwrap_err()
+    }
+
+    /// Wraps `source` as the failed second of two attachments.
+    fn failed_attachment(source: Error) -> Error {
+        Error::AttachmentUpload {
+            index: 1,
+            name: "b.png".to_string(),
+            uploaded: vec!["/contrib_service/ttl_1d/a".to_string()],
+            source: Box::new(source),
+        }
+    }
+
+    #[tokio::test]
+    async fn predicates_look_through_wrapped_errors() {
+        let timeout = Error::Network(timed_out_request().await);
+        assert!(timeout.is_timeout());
+        assert!(timeout.is_network_error());
+        assert!(Error::Timeout.is_timeout());
+
+        let upload = failed_attachment(Error::Network(timed_out_request().await));
+        assert!(upload.is_timeout());
+        assert!(upload.is_network_error());
+        assert!(upload.is_upload_error());
+
+        let upload = failed_attachment(Error::Upload("status: 500".to_string()));
+        assert!(!upload.is_timeout());
+        assert!(!upload.is_network_error());
+        assert!(!Error::Parse("bad frame".to_string()).is_timeout());
+    }
+
+    #[test]
+    fn unavailable_shares_are_not_caller_errors() {
+        assert_eq!(
+            Error::ShareUnavailable("abc123".to_string()).error_category(),
+            ErrorCategory::Unavailable
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Event notifications emitted by the chatbot client.
+
+use crate::client::ResponseStats;
+use crate::enums::Model;
+use crate::error::ErrorCategory;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Notification describing something the client did.
+///
+/// Subscribe with [`AsyncChatbotBuilder::on_event`](crate::client::AsyncChatbotBuilder::on_event).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Event {
+    /// A chat response was received and parsed.
+    ResponseReceived {
+        /// Conversation the response belongs to.
+        conversation_id: String,
+        /// Size and timing statistics for the exchange.
+        stats: ResponseStats,
+    },
+    /// An operation failed.
+    ErrorOccurred {
+        /// Category of the error.
+        kind: ErrorCategory,
+        /// Human-readable error message.
+        message: String,
+    },
+    /// The __Secure-1PSIDTS cookie was rotated.
+    CookieRotated {
+        /// The new __Secure-1PSIDTS value.
+        secure_1psidts: String,
+    },
+    /// A cookie value looks suspicious but was accepted.
+    CookieWarning {
+        /// Name of the cookie.
+        cookie: &'static str,
+        /// What looks wrong.
+        message: &'static str,
+    },
+    /// The conversation state was reset.
+    ConversationReset,
+    /// The next request is being delayed by the pacing policy.
+    RequestDelayed {
+        /// How long the request will wait before being sent.
+        wait: Duration,
+    },
+    /// An attached image was resized before uploading.
+    ///
+    /// Emitted just before the upload, whose [`Event::UploadCompleted`]
+    /// reports the resized byte count.
+    #[cfg(feature = "image")]
+    ImageResized {
+        /// Original and uploaded dimensions and sizes.
+        resize: crate::imaging::ImageResize,
+    },
+    /// A message is being retried with the next model of the fallback chain.
+    ModelFallback {
+        /// The model that could not serve the message.
+        from: Model,
+        /// The model tried next.
+        to: Model,
+        /// Why `from` could not serve the message.
+        reason: String,
+    },
+    /// Upload of one of several attachments started.
+    UploadStarted {
+        /// Position of the attachment.
+        index: usize,
+        /// Name of the attachment.
+        name: String,
+        /// Size of the attachment in bytes.
+        size: usize,
+    },
+    /// Part of an attachment was handed to the connection.
+    UploadProgress {
+        /// Position of the attachment.
+        index: usize,
+        /// Bytes sent so far.
+        sent: usize,
+        /// Size of the attachment in bytes.
+        total: usize,
+    },
+    /// Upload of one of several attachments finished.
+    UploadFinished {
+        /// Position of the attachment.
+        index: usize,
+        /// Identifier returned by the upload server.
+        id: String,
+    },
+    /// A file upload finished.
+    UploadCompleted {
+        /// Identifier returned by the upload server.
+        upload_id: String,
+        /// Size of the uploaded data in bytes.
+        bytes: usize,
+    },
+}
+
+/// Shared event callback type.
+pub(crate) type EventCallback = Arc<dyn Fn(Event) + Send + Sync>;
+
+/// Dispatches events to registered callbacks.
+#[derive(Clone, Default)]
+pub(crate) struct EventEmitter {
+    callbacks: Vec<EventCallback>,
+}
+
+impl EventEmitter {
+    /// Registers a callback.
+    pub(crate) fn subscribe(&mut self, callback: EventCallback) {
+        self.callbacks.push(callback);
+    }
+
+    /// Delivers an event to every callback.
+    ///
+    /// Must be called without holding internal state borrows so callbacks can
+    /// call back into the client. Panics inside callbacks are caught and ignored.
+    pub(crate) fn emit(&self, event: Event) {
+        for callback in &self.callbacks {
+            let event = event.clone();
+            let _ = catch_unwind(AssertUnwindSafe(|| callback(event)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{builder, generate_reply, MockResponse, MockServer, GENERATE_PATH};
+
+    use std::sync::Mutex;
+
+    /// Names of the events a client emitted, in order.
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    fn name(event: &Event) -> String {
+        let debug = format!("{:?}", event);
+        debug
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn scripted_conversation_emits_events_in_order() {
+        let server = MockServer::routes(vec![
+            (
+                GENERATE_PATH,
+                vec![
+                    MockResponse::ok(generate_reply("c_1", "r_1", &[("rc_1", "One")])),
+                    MockResponse::ok(generate_reply("c_1", "r_2", &[("rc_2", "Two")])),
+                    MockResponse::new(500, "server error"),
+                ],
+            ),
+            (
+                "/upload",
+                vec![MockResponse::ok("/contrib_service/ttl_1d/img")],
+            ),
+        ])
+        .await;
+        let log: Log = Arc::default();
+        let recorded = Arc::clone(&log);
+        let mut chatbot = builder(&server)
+            .on_event(move |event| recorded.lock().unwrap().push(name(&event)))
+            .build()
+            .await
+            .unwrap();
+
+        chatbot.ask("First", None).await.unwrap();
+        chatbot.ask("Second", Some(b"GIF89a")).await.unwrap();
+        assert!(chatbot.ask("Third", None).await.is_err());
+        chatbot.reset();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "ResponseReceived",
+                "UploadCompleted",
+                "ResponseReceived",
+                "ErrorOccurred",
+                "ConversationReset",
+            ]
+        );
+    }
+
+    #[test]
+    fn panicking_callback_does_not_stop_delivery() {
+        let log: Log = Arc::default();
+        let recorded = Arc::clone(&log);
+        let mut emitter = EventEmitter::default();
+        emitter.subscribe(Arc::new(|_| panic!("callback failed")));
+        emitter.subscribe(Arc::new(move |event| {
+            recorded.lock().unwrap().push(name(&event))
+        }));
+
+        emitter.emit(Event::ConversationReset);
+        emitter.emit(Event::ConversationReset);
+
+        assert_eq!(*log.lock().unwrap(), ["ConversationReset"; 2]);
+    }
+
+    #[tokio::test]
+    async fn panicking_callback_does_not_affect_the_client() {
+        let server = MockServer::routes(vec![(
+            GENERATE_PATH,
+            vec![MockResponse::ok(generate_reply(
+                "c_1",
+                "r_1",
+                &[("rc_1", "Hi")],
+            ))],
+        )])
+        .await;
+        let mut chatbot = builder(&server)
+            .on_event(|_| panic!("callback failed"))
+            .build()
+            .await
+            .unwrap();
+
+        let response = chatbot.ask("Hello", None).await.unwrap();
+        assert_eq!(response.content, "Hi");
+        assert_eq!(chatbot.conversation_id(), "c_1");
+    }
+}
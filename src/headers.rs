@@ -0,0 +1,320 @@
+//! Composition of the headers sent to each endpoint.
+
+use crate::enums::{gemini_headers, rotate_cookies_headers, upload_headers, Endpoint};
+use crate::error::{Error, Result};
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+use std::collections::HashMap;
+
+/// Headers sent to each endpoint, built from layers.
+///
+/// [`headers_for`](Self::headers_for) applies the layers in a fixed order,
+/// each replacing headers of the same name from the previous ones:
+///
+/// 1. Base browser headers, for the gemini.google.com endpoints
+///    (`Init`, `Generate`, `BatchExecute` and `RotateCookies`); uploads go
+///    to another host and get none.
+/// 2. Endpoint headers, such as the JSON content type of `RotateCookies` and
+///    the default `push-id` of `Upload`.
+/// 3. Overrides for all endpoints, from [`set`](Self::set).
+/// 4. Overrides for one endpoint, from [`set_for`](Self::set_for).
+/// 5. Per-request headers, such as the model header or a custom `push-id`.
+///
+/// # Example
+/// ```
+/// use gemini_chat_api::{Endpoint, HeaderProfile};
+/// use reqwest::header::{HeaderMap, USER_AGENT};
+///
+/// let mut profile = HeaderProfile::new();
+/// profile.set_for(Endpoint::Upload, "user-agent", "uploader/1.0").unwrap();
+///
+/// let upload = profile.headers_for(Endpoint::Upload, &HeaderMap::new());
+/// assert_eq!(upload[USER_AGENT], "uploader/1.0");
+/// let init = profile.headers_for(Endpoint::Init, &HeaderMap::new());
+/// assert!(init[USER_AGENT].to_str().unwrap().contains("Chrome"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct HeaderProfile {
+    base: HeaderMap,
+    overrides: HeaderMap,
+    endpoint_overrides: HashMap<Endpoint, HeaderMap>,
+}
+
+impl Default for HeaderProfile {
+    fn default() -> Self {
+        Self {
+            base: gemini_headers(),
+            overrides: HeaderMap::new(),
+            endpoint_overrides: HashMap::new(),
+        }
+    }
+}
+
+impl HeaderProfile {
+    /// Creates a profile with the built-in Chrome headers and no overrides.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Overrides a header for all endpoints.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if the name or value is not a valid header.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<&mut Self> {
+        let (name, value) = parse_header(name, value)?;
+        self.overrides.insert(name, value);
+        Ok(self)
+    }
+
+    /// Overrides a header for one endpoint, e.g. a different User-Agent for uploads.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if the name or value is not a valid header.
+    pub fn set_for(&mut self, endpoint: Endpoint, name: &str, value: &str) -> Result<&mut Self> {
+        let (name, value) = parse_header(name, value)?;
+        self.endpoint_overrides
+            .entry(endpoint)
+            .or_default()
+            .insert(name, value);
+        Ok(self)
+    }
+
+    /// Removes all overrides, keeping the base headers.
+    pub fn clear_overrides(&mut self) {
+        self.overrides.clear();
+        self.endpoint_overrides.clear();
+    }
+
+    /// Returns the final headers for a request to `endpoint`.
+    ///
+    /// `request` holds per-request headers, which take precedence over
+    /// every other layer.
+    pub fn headers_for(&self, endpoint: Endpoint, request: &HeaderMap) -> HeaderMap {
+        let mut headers = match endpoint {
+            Endpoint::Init
+            | Endpoint::Generate
+            | Endpoint::BatchExecute
+            | Endpoint::RotateCookies => self.base.clone(),
+            Endpoint::Upload => HeaderMap::new(),
+        };
+        let endpoint_headers = match endpoint {
+            Endpoint::Init | Endpoint::Generate | Endpoint::BatchExecute => HeaderMap::new(),
+            Endpoint::RotateCookies => rotate_cookies_headers(),
+            Endpoint::Upload => upload_headers(),
+        };
+        let layers = [
+            Some(&endpoint_headers),
+            Some(&self.overrides),
+            self.endpoint_overrides.get(&endpoint),
+            Some(request),
+        ];
+        for layer in layers.into_iter().flatten() {
+            for (name, value) in layer {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+        headers
+    }
+}
+
+/// Validates a header name and value.
+fn parse_header(name: &str, value: &str) -> Result<(HeaderName, HeaderValue)> {
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| Error::InvalidInput(format!("invalid header name: {:?}", name)))?;
+    let value = HeaderValue::from_str(value)
+        .map_err(|_| Error::InvalidInput(format!("invalid value for header {}", name)))?;
+    Ok((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::AUTHORIZATION;
+
+    const ENDPOINTS: [Endpoint; 5] = [
+        Endpoint::Init,
+        Endpoint::Generate,
+        Endpoint::RotateCookies,
+        Endpoint::Upload,
+        Endpoint::BatchExecute,
+    ];
+
+    #[test]
+    fn no_endpoint_sends_authorization_by_default() {
+        let profile = HeaderProfile::new();
+        for endpoint in ENDPOINTS {
+            let headers = profile.headers_for(endpoint, &HeaderMap::new());
+            assert!(!headers.contains_key(AUTHORIZATION), "{:?}", endpoint);
+        }
+    }
+
+    #[test]
+    fn request_headers_reach_only_their_request() {
+        let profile = HeaderProfile::new();
+        let mut request = HeaderMap::new();
+        request.insert(AUTHORIZATION, HeaderValue::from_static("SAPISIDHASH 1_ab"));
+        let headers = profile.headers_for(Endpoint::BatchExecute, &request);
+        assert_eq!(headers[AUTHORIZATION], "SAPISIDHASH 1_ab");
+        let headers = profile.headers_for(Endpoint::Generate, &HeaderMap::new());
+        assert!(!headers.contains_key(AUTHORIZATION));
+    }
+
+    /// The built-in browser headers of the gemini.google.com endpoints.
+    const BROWSER: [(&str, &str); 13] = [
+        (
+            "content-type",
+            "application/x-www-form-urlencoded;charset=utf-8",
+        ),
+        ("origin", "https://gemini.google.com"),
+        ("referer", "https://gemini.google.com/"),
+        ("x-same-domain", "1"),
+        (
+            "user-agent",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+             (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+        ),
+        ("accept", "*/*"),
+        ("accept-language", "en-US,en;q=0.9"),
+        (
+            "sec-ch-ua",
+            "\"Not_A Brand\";v=\"8\", \"Chromium\";v=\"120\", \"Google Chrome\";v=\"120\"",
+        ),
+        ("sec-ch-ua-mobile", "?0"),
+        ("sec-ch-ua-platform", "\"Windows\""),
+        ("sec-fetch-dest", "empty"),
+        ("sec-fetch-mode", "cors"),
+        ("sec-fetch-site", "same-origin"),
+    ];
+
+    /// `base` with `changes` applied, as a header map.
+    fn expected(
+        base: &[(&'static str, &'static str)],
+        changes: &[(&'static str, &'static str)],
+    ) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in base.iter().chain(changes) {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    /// Headers for `endpoint` from the default profile without request headers.
+    fn default_headers(endpoint: Endpoint) -> HeaderMap {
+        HeaderProfile::default().headers_for(endpoint, &HeaderMap::new())
+    }
+
+    #[test]
+    fn init_endpoint_headers() {
+        assert_eq!(default_headers(Endpoint::Init), expected(&BROWSER, &[]));
+    }
+
+    #[test]
+    fn generate_endpoint_headers() {
+        assert_eq!(default_headers(Endpoint::Generate), expected(&BROWSER, &[]));
+    }
+
+    #[test]
+    fn batch_execute_endpoint_headers() {
+        assert_eq!(
+            default_headers(Endpoint::BatchExecute),
+            expected(&BROWSER, &[])
+        );
+    }
+
+    #[test]
+    fn rotate_cookies_endpoint_headers() {
+        assert_eq!(
+            default_headers(Endpoint::RotateCookies),
+            expected(&BROWSER, &[("content-type", "application/json")])
+        );
+    }
+
+    #[test]
+    fn upload_endpoint_headers() {
+        assert_eq!(
+            default_headers(Endpoint::Upload),
+            expected(&[("push-id", "feeds/mcudyrk2a4khkz")], &[])
+        );
+    }
+
+    #[test]
+    fn user_overrides_replace_endpoint_headers() {
+        let mut profile = HeaderProfile::new();
+        profile
+            .set("user-agent", "custom/1.0")
+            .unwrap()
+            .set("accept-language", "de-DE")
+            .unwrap();
+        profile
+            .set_for(Endpoint::Generate, "user-agent", "generate/2.0")
+            .unwrap()
+            .set_for(Endpoint::RotateCookies, "content-type", "text/plain")
+            .unwrap();
+
+        let overridden = [("user-agent", "custom/1.0"), ("accept-language", "de-DE")];
+        assert_eq!(
+            profile.headers_for(Endpoint::Init, &HeaderMap::new()),
+            expected(&BROWSER, &overridden)
+        );
+        assert_eq!(
+            profile.headers_for(Endpoint::Generate, &HeaderMap::new()),
+            expected(
+                &BROWSER,
+                &[("accept-language", "de-DE"), ("user-agent", "generate/2.0")]
+            )
+        );
+        assert_eq!(
+            profile.headers_for(Endpoint::RotateCookies, &HeaderMap::new()),
+            expected(
+                &BROWSER,
+                &[
+                    ("user-agent", "custom/1.0"),
+                    ("accept-language", "de-DE"),
+                    ("content-type", "text/plain"),
+                ]
+            )
+        );
+        assert_eq!(
+            profile.headers_for(Endpoint::Upload, &HeaderMap::new()),
+            expected(
+                &[("push-id", "feeds/mcudyrk2a4khkz")],
+                &[("user-agent", "custom/1.0"), ("accept-language", "de-DE")]
+            )
+        );
+    }
+
+    #[test]
+    fn request_headers_take_precedence() {
+        let mut profile = HeaderProfile::new();
+        profile
+            .set_for(Endpoint::Generate, "user-agent", "generate/2.0")
+            .unwrap();
+        let request = expected(
+            &[
+                ("user-agent", "request/3.0"),
+                ("x-goog-ext-525001261-jspb", "[1,null,null,null,\"abc\"]"),
+            ],
+            &[],
+        );
+        assert_eq!(
+            profile.headers_for(Endpoint::Generate, &request),
+            expected(
+                &BROWSER,
+                &[
+                    ("user-agent", "request/3.0"),
+                    ("x-goog-ext-525001261-jspb", "[1,null,null,null,\"abc\"]"),
+                ]
+            )
+        );
+
+        let push_id = expected(&[("push-id", "feeds/custom")], &[]);
+        assert_eq!(
+            profile.headers_for(Endpoint::Upload, &push_id),
+            expected(&[("push-id", "feeds/custom")], &[])
+        );
+    }
+}
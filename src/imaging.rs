@@ -0,0 +1,202 @@
+//! Client-side image preprocessing, available with the `image` feature.
+
+use crate::error::{Error, Result};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use std::io::Cursor;
+
+/// Lowest JPEG quality tried before the image is scaled down further.
+const MIN_JPEG_QUALITY: u8 = 40;
+
+/// Image data ready to be sent with [`AsyncChatbot::ask`](crate::client::AsyncChatbot::ask).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    /// Encoded image bytes.
+    pub data: Vec<u8>,
+    /// MIME type of `data`.
+    pub mime_type: &'static str,
+}
+
+impl Attachment {
+    /// Encodes an in-memory image.
+    ///
+    /// # Errors
+    /// Returns `Error::Upload` if the image cannot be encoded in `format`.
+    pub fn from_image(img: &DynamicImage, format: ImageFormat) -> Result<Self> {
+        let mut data = Vec::new();
+        img.write_to(&mut Cursor::new(&mut data), format)
+            .map_err(|e| Error::Upload(format!("failed to encode image: {}", e)))?;
+        Ok(Self {
+            data,
+            mime_type: format.to_mime_type(),
+        })
+    }
+}
+
+/// Transformation applied by automatic resizing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageResize {
+    /// Width of the original image, after EXIF orientation.
+    pub original_width: u32,
+    /// Height of the original image, after EXIF orientation.
+    pub original_height: u32,
+    /// Size of the original data in bytes.
+    pub original_bytes: usize,
+    /// Width of the uploaded image.
+    pub width: u32,
+    /// Height of the uploaded image.
+    pub height: u32,
+    /// Size of the uploaded data in bytes.
+    pub bytes: usize,
+}
+
+/// Downscales and re-encodes an image to fit the given limits.
+///
+/// EXIF orientation is applied so the output is upright, and the aspect ratio
+/// is preserved. The result is a JPEG whose quality is lowered, then whose
+/// dimensions are reduced, until it fits `max_bytes`.
+///
+/// # Returns
+/// `None` if the image already fits both limits and needs no rotation.
+///
+/// # Errors
+/// Returns `Error::Upload` if the image cannot be decoded or cannot be
+/// brought under `max_bytes`.
+pub(crate) fn resize_for_upload(
+    data: &[u8],
+    max_dimension: u32,
+    max_bytes: usize,
+) -> Result<Option<(Vec<u8>, ImageResize)>> {
+    let decode_error =
+        |e: image::ImageError| Error::Upload(format!("failed to decode image: {}", e));
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| Error::Upload(e.to_string()))?
+        .into_decoder()
+        .map_err(decode_error)?;
+    let orientation = decoder.orientation().map_err(decode_error)?;
+    let mut img = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+
+    let upright = orientation == image::metadata::Orientation::NoTransforms;
+    if upright && img.width().max(img.height()) <= max_dimension && data.len() <= max_bytes {
+        return Ok(None);
+    }
+    img.apply_orientation(orientation);
+    let (original_width, original_height) = (img.width(), img.height());
+
+    let mut target = max_dimension.max(1);
+    loop {
+        let scaled = if img.width().max(img.height()) > target {
+            img.resize(target, target, FilterType::Lanczos3)
+        } else {
+            img.clone()
+        };
+        let rgb = DynamicImage::ImageRgb8(scaled.to_rgb8());
+
+        let mut quality = 85;
+        loop {
+            let mut encoded = Vec::new();
+            rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, quality))
+                .map_err(|e| Error::Upload(format!("failed to encode image: {}", e)))?;
+            if encoded.len() <= max_bytes {
+                let resize = ImageResize {
+                    original_width,
+                    original_height,
+                    original_bytes: data.len(),
+                    width: rgb.width(),
+                    height: rgb.height(),
+                    bytes: encoded.len(),
+                };
+                return Ok(Some((encoded, resize)));
+            }
+            if quality <= MIN_JPEG_QUALITY {
+                break;
+            }
+            quality = quality.saturating_sub(15).max(MIN_JPEG_QUALITY);
+        }
+
+        if target <= 64 {
+            return Err(Error::Upload(format!(
+                "image cannot be reduced below {} bytes",
+                max_bytes
+            )));
+        }
+        target = rgb.width().max(rgb.height()) * 3 / 4;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// A PNG of `width` x `height` pixels of deterministic noise, which
+    /// compresses poorly.
+    fn noise_png(width: u32, height: u32) -> Vec<u8> {
+        let mut state: u32 = 0x2545_f491;
+        let img = RgbImage::from_fn(width, height, |_, _| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let [r, g, b, _] = state.to_le_bytes();
+            Rgb([r, g, b])
+        });
+        Attachment::from_image(&DynamicImage::ImageRgb8(img), ImageFormat::Png)
+            .unwrap()
+            .data
+    }
+
+    fn dimensions(data: &[u8]) -> (u32, u32) {
+        let img = image::load_from_memory(data).unwrap();
+        (img.width(), img.height())
+    }
+
+    #[test]
+    fn fitting_image_is_left_alone() {
+        let data = noise_png(100, 50);
+        assert!(resize_for_upload(&data, 100, data.len()).unwrap().is_none());
+    }
+
+    #[test]
+    fn downscales_to_max_dimension() {
+        let data = noise_png(400, 200);
+        let (encoded, resize) = resize_for_upload(&data, 128, usize::MAX).unwrap().unwrap();
+        assert_eq!((resize.width, resize.height), (128, 64));
+        assert_eq!((resize.original_width, resize.original_height), (400, 200));
+        assert_eq!(resize.original_bytes, data.len());
+        assert_eq!(resize.bytes, encoded.len());
+        assert_eq!(dimensions(&encoded), (128, 64));
+        assert_eq!(image::guess_format(&encoded).unwrap(), ImageFormat::Jpeg);
+
+        // Portrait images are limited by their height
+        let (_, resize) = resize_for_upload(&noise_png(90, 300), 100, usize::MAX)
+            .unwrap()
+            .unwrap();
+        assert_eq!((resize.width, resize.height), (30, 100));
+    }
+
+    #[test]
+    fn meets_the_byte_budget() {
+        let data = noise_png(300, 300);
+        for max_bytes in [40_000, 15_000, 5_000] {
+            let (encoded, resize) = resize_for_upload(&data, 300, max_bytes).unwrap().unwrap();
+            assert!(
+                encoded.len() <= max_bytes,
+                "{} > {}",
+                encoded.len(),
+                max_bytes
+            );
+            assert_eq!(dimensions(&encoded), (resize.width, resize.height));
+            assert!(resize.width <= 300 && resize.width == resize.height);
+        }
+    }
+
+    #[test]
+    fn impossible_budget_fails() {
+        let error = resize_for_upload(&noise_png(200, 200), 200, 100).unwrap_err();
+        assert!(matches!(error, Error::Upload(_)), "{:?}", error);
+        assert!(error.to_string().contains("100 bytes"), "{}", error);
+    }
+}
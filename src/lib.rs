@@ -23,13 +23,91 @@
 //! }
 //! ```
 
+pub mod activity;
+pub mod builder;
 pub mod client;
+pub mod config;
+pub mod crypto;
 pub mod enums;
 pub mod error;
+pub mod events;
+pub mod headers;
+#[cfg(feature = "image")]
+pub mod imaging;
+mod pacing;
+mod platform;
+pub mod prompts;
+mod rpc;
+pub mod session_cache;
+#[cfg(test)]
+mod test_support;
+pub mod usage;
 pub mod utils;
 
 // Re-exports for convenience
-pub use client::{AsyncChatbot, ChatResponse, Choice, SavedConversation};
-pub use enums::{Endpoint, Model};
-pub use error::{Error, Result};
-pub use utils::load_cookies;
+pub use activity::ActivityRecord;
+pub use builder::MultimodalMessageBuilder;
+pub use client::{
+    build_generate_request, merge_choices_by_majority, AskOptions, AsyncChatbot,
+    AsyncChatbotBuilder, ChatResponse, Choice, ConversationMetadata, ConversationState,
+    HealthStatus, RequestParts, ResponseStats, Role, RolePlayConfig, SavedConversation,
+    StylePreset, Turn, TurnAttachment,
+};
+pub use config::ClientConfig;
+pub use enums::{Endpoint, FeedbackType, Model, ModelCapabilities};
+pub use error::{Error, ErrorCategory, Result};
+pub use events::Event;
+pub use headers::HeaderProfile;
+#[cfg(feature = "image")]
+pub use imaging::{Attachment, ImageResize};
+pub use prompts::{
+    Citation, CitedResponse, CodeGenerationResponse, ComparisonPreference, ComparisonResponse,
+    ContextualMessage, FactsResponse, FormatKind, FormattedResponse, LongFormResponse,
+    OutlineFirstConfig, OutputFormat, ReflectionTrace, StepByStepResponse, TranslationResponse,
+    VotingResponse,
+};
+pub use session_cache::SessionCache;
+pub use usage::{InMemoryUsageTracker, UsageRecord, UsageTracker};
+pub use utils::{
+    chunk_text, count_words, detect_code_language, diff_responses, diff_to_markdown,
+    estimate_tokens, extract_code_blocks, extract_tables, language_family, load_cookies_from_env,
+    markdown_to_plain_text, mask_pii, resolve_proxy, sanitize_untrusted, sapisid_hash,
+    truncate_utf8, upload_file_chunked, CodeBlock, DiffSpan, EnvProxy, LanguageFamily,
+    MarkdownTable, PiiMap, SanitizePolicy, UploadProgress,
+};
+#[cfg(feature = "hash-utils")]
+pub use utils::{hash_conversation, hash_message, hash_message_hex};
+#[cfg(not(target_arch = "wasm32"))]
+pub use utils::{
+    load_cookies, load_cookies_from_json5, load_cookies_optional, load_sapisid,
+    load_session_cookies,
+};
+
+/// The most commonly used items, for glob import.
+///
+/// ```
+/// use gemini_chat_api::prelude::*;
+/// ```
+pub mod prelude {
+    /// The chat client itself.
+    pub use crate::client::AsyncChatbot;
+    /// Configures clients beyond what `AsyncChatbot::new` accepts.
+    pub use crate::client::AsyncChatbotBuilder;
+    /// Returned by every `ask` call.
+    pub use crate::client::ChatResponse;
+    /// Alternative drafts inside a `ChatResponse`.
+    pub use crate::client::Choice;
+    /// Entries of saved conversation files.
+    pub use crate::client::SavedConversation;
+    /// Selects which Gemini model answers.
+    pub use crate::enums::Model;
+    /// The crate's error type, needed to handle failures.
+    pub use crate::error::Error;
+    /// Result alias used by every fallible function.
+    pub use crate::error::Result;
+    /// Reads cookies from a browser export file.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub use crate::utils::load_cookies;
+    /// Reads cookies from environment variables.
+    pub use crate::utils::load_cookies_from_env;
+}
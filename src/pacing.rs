@@ -0,0 +1,54 @@
+//! Minimum-interval request pacing.
+
+use crate::platform::Instant;
+
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Enforces a floor between consecutive requests.
+///
+/// Clones share the same clock, so every handle of a session is paced together.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pacer {
+    interval: Duration,
+    jitter: Duration,
+    next_slot: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Pacer {
+    /// Creates a pacer with the given minimum interval and maximum extra jitter.
+    pub(crate) fn new(interval: Duration, jitter: Duration) -> Self {
+        Self {
+            interval,
+            jitter,
+            next_slot: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Reserves the next request slot and returns how long the caller must wait.
+    ///
+    /// The lock is only held while computing the slot, never while sleeping.
+    pub(crate) async fn reserve(&self) -> Duration {
+        if self.interval.is_zero() && self.jitter.is_zero() {
+            return Duration::ZERO;
+        }
+
+        let now = Instant::now();
+        let mut next_slot = self.next_slot.lock().await;
+        let start = match *next_slot {
+            Some(slot) if slot > now => slot,
+            _ => now,
+        };
+
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+        };
+        *next_slot = Some(start + self.interval + jitter);
+
+        start - now
+    }
+}
@@ -0,0 +1,93 @@
+//! Differences between native targets and the browser.
+//!
+//! On `wasm32` the client runs on reqwest's fetch backend. The browser
+//! attaches the account's cookies, follows redirects and chooses proxies, so
+//! the cookie jar, redirect policy and proxy settings only exist natively.
+//! The clock and timers come from the browser as well.
+
+use std::time::Duration;
+
+use reqwest::{ClientBuilder, RequestBuilder, Response};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(target_arch = "wasm32")]
+pub(crate) use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Waits for `duration` without blocking the runtime.
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::time::sleep(duration).await;
+    #[cfg(target_arch = "wasm32")]
+    gloo_timers::future::sleep(duration).await;
+}
+
+/// Sends a request, applying `timeout` unless the request has its own.
+///
+/// Native clients carry their timeout, so `timeout` only applies in the
+/// browser, where requests are also sent with the page's credentials.
+pub(crate) async fn send(request: RequestBuilder, timeout: Duration) -> reqwest::Result<Response> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let _ = timeout;
+        request.send().await
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let (client, request) = request.fetch_credentials_include().build_split();
+        let mut request = request?;
+        request.timeout_mut().get_or_insert(timeout);
+        client.execute(request).await
+    }
+}
+
+/// Routes a client through `proxy`, or connects directly without one.
+///
+/// The browser ignores both and uses its own proxy settings.
+pub(crate) fn with_proxy(
+    builder: ClientBuilder,
+    proxy: Option<&str>,
+) -> reqwest::Result<ClientBuilder> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let builder = builder.no_proxy();
+        match proxy {
+            Some(proxy_url) => Ok(builder.proxy(reqwest::Proxy::all(proxy_url)?)),
+            None => Ok(builder),
+        }
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let _ = proxy;
+        Ok(builder)
+    }
+}
+
+/// Stops a client at login and consent redirects; see
+/// [`redirect_policy`](crate::utils::redirect_policy).
+///
+/// The browser follows every redirect, and login pages are recognized by
+/// their content instead.
+pub(crate) fn stop_at_login(builder: ClientBuilder) -> ClientBuilder {
+    #[cfg(not(target_arch = "wasm32"))]
+    return builder.redirect(crate::utils::redirect_policy());
+    #[cfg(target_arch = "wasm32")]
+    builder
+}
+
+/// Value of the cookie `name` set by a response.
+///
+/// The browser stores cookies itself and hides `Set-Cookie` from fetch, so
+/// this is always `None` there.
+pub(crate) fn set_cookie(response: &Response, name: &str) -> Option<String> {
+    #[cfg(not(target_arch = "wasm32"))]
+    return response
+        .cookies()
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value().to_string());
+    #[cfg(target_arch = "wasm32")]
+    {
+        let _ = (response, name);
+        None
+    }
+}
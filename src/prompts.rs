@@ -0,0 +1,1925 @@
+//! Prompt-templated workflows built on top of [`AsyncChatbot::ask`].
+
+use crate::client::{rfc3339_now, AsyncChatbot, ChatResponse, TOKEN_BUDGET_WARNING};
+use crate::error::{Error, Result};
+use crate::utils::{
+    chunk_text, detect_image_mime, detect_language, estimate_tokens, language_name,
+    markdown_to_plain_text, mask_pii, PiiMap,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::sync::OnceLock;
+
+const CITATION_INSTRUCTION: &str =
+    "For each factual claim, provide the basis for it in [brackets].";
+
+const FACTS_INSTRUCTION: &str =
+    "Use ONLY the following facts to answer the question. Do not hallucinate.\n\n";
+
+const FACTS_STRICT_INSTRUCTION: &str =
+    "If the facts don't contain enough information, say 'I don't know'.";
+
+const IMAGE_ANALYSIS_PROMPT: &str = "Analyze this image. Respond with JSON only, in this form: \
+{\"objects_detected\": [\"object\"], \"text_extracted\": \"text in the image, or null if none\", \
+\"dominant_colors\": [\"color\"], \"scene_description\": \"one sentence describing the scene\"}";
+
+/// Languages accepted by [`AsyncChatbot::ask_code_generation`], lowercase.
+const CODE_LANGUAGES: &[&str] = &[
+    "bash",
+    "c",
+    "c#",
+    "c++",
+    "clojure",
+    "cpp",
+    "csharp",
+    "css",
+    "dart",
+    "elixir",
+    "erlang",
+    "f#",
+    "go",
+    "haskell",
+    "html",
+    "java",
+    "javascript",
+    "js",
+    "julia",
+    "kotlin",
+    "lua",
+    "matlab",
+    "objective-c",
+    "ocaml",
+    "perl",
+    "php",
+    "powershell",
+    "python",
+    "r",
+    "ruby",
+    "rust",
+    "scala",
+    "shell",
+    "sql",
+    "swift",
+    "typescript",
+    "ts",
+    "zig",
+];
+
+/// Most turns accepted by [`AsyncChatbot::ask_with_explicit_context`].
+const MAX_EXPLICIT_CONTEXT_TURNS: usize = 20;
+
+const DEFINITIVE_INSTRUCTION: &str = "Please be more definitive and avoid hedging language.";
+
+/// Most critique and improvement rounds of [`AsyncChatbot::ask_with_reflection`].
+const MAX_REFLECTION_TURNS: u8 = 3;
+
+const CRITIQUE_PROMPT: &str = "Critique your previous answer. What are its weaknesses?";
+
+const IMPROVE_PROMPT: &str = "Improve your answer based on the critique.";
+
+const STEP_BY_STEP_INSTRUCTION: &str =
+    "Think through this step by step. End with 'Final answer: ...' on its own line.";
+
+/// A claim and the basis Gemini gave for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Citation {
+    /// The sentence the annotation is attached to (empty for factuality URLs).
+    pub claim: String,
+    /// The bracketed basis or source URL, if any.
+    pub source_hint: Option<String>,
+}
+
+/// Response with parsed citations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CitedResponse {
+    /// Response content including the bracketed annotations.
+    pub content: String,
+    /// Citations parsed from the content and factuality data.
+    pub citations: Vec<Citation>,
+    /// The underlying response.
+    pub raw: ChatResponse,
+}
+
+impl CitedResponse {
+    /// Returns the content with bracketed annotations removed.
+    ///
+    /// Markdown links (`[text](url)`) are left untouched.
+    pub fn uncited_content(&self) -> String {
+        let mut out = String::with_capacity(self.content.len());
+        let mut last = 0;
+        for (start, end, _) in bracket_annotations(&self.content) {
+            out.push_str(self.content[last..start].trim_end_matches([' ', '\t']));
+            last = end;
+        }
+        out.push_str(&self.content[last..]);
+        out
+    }
+}
+
+/// Response split into reasoning steps and a final answer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepByStepResponse {
+    /// Numbered or bulleted reasoning steps, without their markers.
+    pub steps: Vec<String>,
+    /// The text following `Final answer:`.
+    pub final_answer: String,
+    /// The underlying response.
+    pub raw: ChatResponse,
+}
+
+/// Every response of a self-reflection exchange.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReflectionTrace {
+    /// The answer to the original message.
+    pub initial: ChatResponse,
+    /// The critique and improved answer of each round, in order.
+    pub reflections: Vec<(ChatResponse, ChatResponse)>,
+    /// The last improved answer, or the initial one if there were no rounds.
+    pub final_answer: ChatResponse,
+}
+
+/// The option Gemini preferred in a comparison.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComparisonPreference {
+    /// The preferred option, as passed to `ask_compare`.
+    pub chosen: String,
+    /// Confidence in `[0.0, 1.0]`; taken from a stated percentage when present.
+    pub confidence: f32,
+}
+
+/// Structured result of comparing two options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComparisonResponse {
+    /// The stated preference, if one could be identified.
+    pub preference: Option<ComparisonPreference>,
+    /// The preference statement, or the full content if the format was not followed.
+    pub reasoning: String,
+    /// Advantages of option A.
+    pub pros_a: Vec<String>,
+    /// Disadvantages of option A.
+    pub cons_a: Vec<String>,
+    /// Advantages of option B.
+    pub pros_b: Vec<String>,
+    /// Disadvantages of option B.
+    pub cons_b: Vec<String>,
+    /// The underlying response.
+    pub raw: ChatResponse,
+}
+
+/// Result of a translation request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TranslationResponse {
+    /// The translated text.
+    pub translated_text: String,
+    /// ISO 639-1 code of the source language, if known or detected.
+    pub detected_source_language: Option<String>,
+    /// The underlying response.
+    pub raw: ChatResponse,
+}
+
+/// Result of asking Gemini to pick one of several options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VotingResponse {
+    /// Zero-based index of the selected candidate.
+    pub selected_index: usize,
+    /// The selected candidate, as passed to `ask_with_voting`.
+    pub selected_value: String,
+    /// The explanation following the selection.
+    pub reasoning: String,
+    /// Confidence in `[0.0, 1.0]`, if a percentage was stated.
+    pub confidence: Option<f32>,
+    /// The underlying response.
+    pub raw: ChatResponse,
+}
+
+/// Prompts used by [`AsyncChatbot::ask_with_outline_first`].
+///
+/// `{topic}` is replaced in both prompts and `{section}` in the expand prompt.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutlineFirstConfig {
+    /// Prompt requesting the outline.
+    pub outline_prompt: String,
+    /// Prompt requesting the expansion of one section.
+    pub expand_prompt_template: String,
+}
+
+impl Default for OutlineFirstConfig {
+    fn default() -> Self {
+        Self {
+            outline_prompt: "Create an outline for: {topic}".to_string(),
+            expand_prompt_template: "Expand this section: {section}\n\nContext: {topic}"
+                .to_string(),
+        }
+    }
+}
+
+/// Long-form document written section by section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LongFormResponse {
+    /// Top-level outline items, without their list markers.
+    pub outline: Vec<String>,
+    /// Expanded text of each outline item, in order.
+    pub sections: Vec<String>,
+    /// The synthesized document.
+    pub final_content: String,
+}
+
+/// Answer restricted to caller-supplied facts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FactsResponse {
+    /// The answer text.
+    pub content: String,
+    /// Whether Gemini said the facts were not enough to answer.
+    pub insufficient_facts: bool,
+    /// The underlying response.
+    pub raw: ChatResponse,
+}
+
+/// Output format requested by [`AsyncChatbot::ask_with_output_format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FormatKind {
+    /// Text without Markdown formatting.
+    PlainText,
+    /// Markdown.
+    Markdown,
+    /// A single JSON value.
+    Json,
+    /// A YAML document.
+    Yaml,
+    /// Comma-separated values.
+    Csv,
+    /// An HTML `<table>`.
+    HtmlTable,
+}
+
+impl FormatKind {
+    /// Returns the name used in prompts.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FormatKind::PlainText => "plain text",
+            FormatKind::Markdown => "Markdown",
+            FormatKind::Json => "JSON",
+            FormatKind::Yaml => "YAML",
+            FormatKind::Csv => "CSV",
+            FormatKind::HtmlTable => "HTML table",
+        }
+    }
+
+    /// Returns the instruction appended to the message.
+    fn instruction(&self) -> &'static str {
+        match self {
+            FormatKind::PlainText => "Respond in plain text without any Markdown formatting.",
+            FormatKind::Markdown => "Respond in Markdown.",
+            FormatKind::Json => {
+                "Respond with valid JSON only, without explanations or code fences."
+            }
+            FormatKind::Yaml => {
+                "Respond with a valid YAML document only, without explanations or code fences."
+            }
+            FormatKind::Csv => {
+                "Respond with CSV only: a header row, then one row per record, \
+                 with the same number of columns in every row. No explanations or code fences."
+            }
+            FormatKind::HtmlTable => {
+                "Respond with a single HTML <table> element only, without explanations or code fences."
+            }
+        }
+    }
+}
+
+/// Output format with an optional schema or column description.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputFormat {
+    /// The format to request.
+    pub kind: FormatKind,
+    /// Schema, example or column list the output should follow.
+    pub schema: Option<String>,
+}
+
+impl OutputFormat {
+    /// Creates a format without a schema.
+    pub fn new(kind: FormatKind) -> Self {
+        Self { kind, schema: None }
+    }
+
+    /// Sets the schema the output should follow.
+    pub fn with_schema(mut self, schema: &str) -> Self {
+        self.schema = Some(schema.to_string());
+        self
+    }
+}
+
+/// Response requested in a specific format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormattedResponse {
+    /// The formatted output, without surrounding code fences or prose.
+    pub content: String,
+    /// The requested format.
+    pub format: FormatKind,
+    /// Whether `content` passed validation for `format`.
+    pub parse_success: bool,
+    /// The underlying response of the last attempt.
+    pub raw: ChatResponse,
+}
+
+/// Structured description of an image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageAnalysisResponse {
+    /// Objects Gemini identified in the image.
+    pub objects_detected: Vec<String>,
+    /// Text found in the image, if any.
+    pub text_extracted: Option<String>,
+    /// Most prominent colors, by name.
+    pub dominant_colors: Vec<String>,
+    /// One-sentence description of the scene.
+    pub scene_description: String,
+    /// The underlying response.
+    pub raw: ChatResponse,
+}
+
+/// Generated code split into its parts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CodeGenerationResponse {
+    /// The main code block, or the whole content if there is none.
+    pub code: String,
+    /// The requested language.
+    pub language: String,
+    /// Prose outside the code blocks, if any.
+    pub explanation: Option<String>,
+    /// The remaining code blocks, assumed to be tests.
+    pub test_cases: Vec<String>,
+    /// The underlying response.
+    pub raw: ChatResponse,
+}
+
+impl CodeGenerationResponse {
+    /// Whether any test code was found.
+    pub fn has_tests(&self) -> bool {
+        !self.test_cases.is_empty()
+    }
+
+    /// Returns the code followed by the test cases, separated by blank lines.
+    pub fn full_source_file(&self) -> String {
+        let mut source = self.code.clone();
+        for test in &self.test_cases {
+            source.push_str("\n\n");
+            source.push_str(test);
+        }
+        source.push('\n');
+        source
+    }
+}
+
+/// Message with context about the user prepended.
+///
+/// Each context piece becomes a sentence before the message, in the order
+/// time, location, custom entries. The time is read when the message is
+/// built.
+///
+/// # Example
+/// ```
+/// use gemini_chat_api::ContextualMessage;
+///
+/// let message = ContextualMessage::new("Any events this weekend?")
+///     .with_location("Utrecht, Netherlands")
+///     .with_custom("Interests", "jazz")
+///     .build();
+/// assert_eq!(
+///     message,
+///     "The user's location is: Utrecht, Netherlands. Interests: jazz. Any events this weekend?"
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ContextualMessage {
+    message: String,
+    time: bool,
+    location: Option<String>,
+    custom: Vec<(String, String)>,
+}
+
+impl ContextualMessage {
+    /// Creates a message without context.
+    pub fn new(message: &str) -> Self {
+        Self {
+            message: message.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Adds `"Today's date and time is {now} UTC."`, with the current time
+    /// in RFC 3339 format.
+    pub fn with_time(mut self) -> Self {
+        self.time = true;
+        self
+    }
+
+    /// Adds `"The user's location is: {location}."`.
+    pub fn with_location(mut self, location: &str) -> Self {
+        self.location = Some(location.to_string());
+        self
+    }
+
+    /// Adds `"{key}: {val}."`.
+    pub fn with_custom(mut self, key: &str, val: &str) -> Self {
+        self.custom.push((key.to_string(), val.to_string()));
+        self
+    }
+
+    /// Assembles the message text.
+    pub fn build(&self) -> String {
+        let mut text = String::new();
+        if self.time {
+            text.push_str(&format!("Today's date and time is {} UTC. ", rfc3339_now()));
+        }
+        if let Some(location) = &self.location {
+            text.push_str(&format!("The user's location is: {}. ", location));
+        }
+        for (key, val) in &self.custom {
+            text.push_str(&format!("{}: {}. ", key, val));
+        }
+        text.push_str(&self.message);
+        text
+    }
+
+    /// Sends the assembled message.
+    pub async fn ask(&self, chatbot: &mut AsyncChatbot) -> Result<ChatResponse> {
+        chatbot.ask(&self.build(), None).await
+    }
+}
+
+impl AsyncChatbot {
+    /// Asks a question with the current date and time prepended.
+    ///
+    /// Helps with questions about "current" events, since the model does not
+    /// know the date. See [`ContextualMessage`] to combine contexts.
+    pub async fn ask_with_time_context(&mut self, message: &str) -> Result<ChatResponse> {
+        ContextualMessage::new(message).with_time().ask(self).await
+    }
+
+    /// Asks a question with the user's location prepended.
+    ///
+    /// See [`ContextualMessage`] to combine contexts.
+    pub async fn ask_with_location_context(
+        &mut self,
+        message: &str,
+        location: &str,
+    ) -> Result<ChatResponse> {
+        ContextualMessage::new(message)
+            .with_location(location)
+            .ask(self)
+            .await
+    }
+
+    /// Asks a question and requests the answer in a specific format.
+    ///
+    /// Appends a format instruction, and the schema if one is set, to the
+    /// message. The output is validated: JSON must parse, YAML must be
+    /// structurally well formed, CSV rows must have consistent columns, an
+    /// HTML table must contain a `<table>` element, and plain text must not
+    /// contain Markdown. Markdown is always accepted. If validation fails,
+    /// `"Your previous response was not valid {format}. Retry."` is sent once
+    /// in the same conversation.
+    ///
+    /// # Returns
+    /// The last attempt, whether or not it passed validation.
+    pub async fn ask_with_output_format(
+        &mut self,
+        message: &str,
+        format: &OutputFormat,
+    ) -> Result<FormattedResponse> {
+        let mut prompt = format!("{}\n\n{}", message, format.kind.instruction());
+        if let Some(schema) = &format.schema {
+            prompt.push_str(&format!("\nFollow this schema:\n{}", schema));
+        }
+
+        let raw = self.ask(&prompt, None).await?;
+        let first = formatted_response(raw, format.kind);
+        if first.parse_success {
+            return Ok(first);
+        }
+
+        let retry = format!(
+            "Your previous response was not valid {}. Retry.",
+            format.kind.name()
+        );
+        let raw = self.ask(&retry, None).await?;
+        Ok(formatted_response(raw, format.kind))
+    }
+
+    /// Asks a question and retries until the answer passes `validator`.
+    ///
+    /// When `validator(&response.content)` returns false, the message is
+    /// sent again as `"{message}\n\n{retry_prompt}"`, up to `max_retries`
+    /// times. `validator` is a plain function, e.g. one checking that the
+    /// answer parses as an email address.
+    ///
+    /// # Returns
+    /// The first response that passes, or the last one with `error: true`
+    /// once the retries are exhausted.
+    pub async fn ask_with_output_validation(
+        &mut self,
+        message: &str,
+        validator: fn(&str) -> bool,
+        retry_prompt: &str,
+        max_retries: u8,
+    ) -> Result<ChatResponse> {
+        let mut response = self.ask(message, None).await?;
+        let retry = format!("{}\n\n{}", message, retry_prompt);
+        let mut retries = 0;
+        while !validator(&response.content) {
+            if retries == max_retries {
+                response.error = true;
+                return Ok(response);
+            }
+            retries += 1;
+            response = self.ask(&retry, None).await?;
+        }
+        Ok(response)
+    }
+
+    /// Asks a question and retries while the answer sounds unsure.
+    ///
+    /// When [`ChatResponse::confidence_heuristic`] is below `min_confidence`,
+    /// the message is sent again with `"Please be more definitive and avoid
+    /// hedging language."` appended, up to `max_retries` times.
+    ///
+    /// # Returns
+    /// The first response meeting the threshold, or the most confident one
+    /// seen once the retries are exhausted; ties keep the earliest.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if `min_confidence` is outside
+    /// `[0.0, 1.0]`.
+    pub async fn ask_with_confidence_threshold(
+        &mut self,
+        message: &str,
+        min_confidence: f32,
+        max_retries: u8,
+    ) -> Result<ChatResponse> {
+        if !(0.0..=1.0).contains(&min_confidence) {
+            return Err(Error::InvalidInput(
+                "min_confidence must be between 0.0 and 1.0".to_string(),
+            ));
+        }
+        let retry = format!("{}\n\n{}", message, DEFINITIVE_INSTRUCTION);
+        let mut best_response = self.ask(message, None).await?;
+        let mut best_confidence = best_response.confidence_heuristic();
+        let mut retries = 0;
+        while best_confidence < min_confidence && retries < max_retries {
+            retries += 1;
+            let response = self.ask(&retry, None).await?;
+            let confidence = response.confidence_heuristic();
+            if confidence > best_confidence {
+                best_response = response;
+                best_confidence = confidence;
+            }
+        }
+        Ok(best_response)
+    }
+
+    /// Asks a question with personal information masked.
+    ///
+    /// The message is masked with [`mask_pii`] before sending, so Gemini
+    /// only sees placeholders such as `[EMAIL_1]`, and placeholders in the
+    /// reply are restored in `content` and the choices.
+    ///
+    /// # Returns
+    /// The restored response and the map used, e.g. to restore follow-ups.
+    pub async fn ask_with_pii_masking(&mut self, message: &str) -> Result<(ChatResponse, PiiMap)> {
+        let (masked, pii_map) = mask_pii(message);
+        let mut response = self.ask(&masked, None).await?;
+        response.content = pii_map.restore(&response.content);
+        for choice in &mut response.choices {
+            choice.content = pii_map.restore(&choice.content);
+        }
+        Ok((response, pii_map))
+    }
+
+    /// Asks a question and cuts the answer to an output token budget.
+    ///
+    /// The web API has no output limit, so the reply is measured with
+    /// [`estimate_tokens`] after it arrives. If it is over `max_output_tokens`,
+    /// the content is cut after the last sentence that fits, when there is
+    /// one, and `"\n[WARNING: response exceeded token budget of
+    /// {max_output_tokens}]"` is appended with `error: true`. See
+    /// [`ChatResponse::was_truncated`].
+    pub async fn ask_with_token_budget(
+        &mut self,
+        message: &str,
+        max_output_tokens: usize,
+    ) -> Result<ChatResponse> {
+        let mut response = self.ask(message, None).await?;
+        if estimate_tokens(&response.content) <= max_output_tokens {
+            return Ok(response);
+        }
+
+        let cut = sentence_ends(&response.content)
+            .take_while(|&end| estimate_tokens(&response.content[..end]) <= max_output_tokens)
+            .last();
+        if let Some(end) = cut {
+            response.content.truncate(end);
+        }
+        response.error = true;
+        response
+            .content
+            .push_str(&format!("{}{}]", TOKEN_BUDGET_WARNING, max_output_tokens));
+        Ok(response)
+    }
+
+    /// Asks for a JSON object matching a JSON Schema.
+    ///
+    /// Appends `"Respond with a JSON object matching this schema:
+    /// {pretty_schema}"` and parses the reply, ignoring a surrounding code
+    /// fence. The result must be an object containing every key listed in
+    /// the schema's top-level `required` array; the schema is not otherwise
+    /// enforced. If it is not, the problem is sent back once in the same
+    /// conversation, asking for a corrected object.
+    ///
+    /// # Errors
+    /// Returns `Error::Parse` describing the problem if the retry is not
+    /// valid either, and the errors of [`ask`](Self::ask).
+    pub async fn ask_with_json_schema(&mut self, message: &str, schema: &Value) -> Result<Value> {
+        let pretty_schema = serde_json::to_string_pretty(schema)?;
+        let prompt = format!(
+            "{}\n\nRespond with a JSON object matching this schema: {}",
+            message, pretty_schema
+        );
+        let raw = self.ask(&prompt, None).await?;
+        let problem = match validate_schema_object(&raw.content, schema) {
+            Ok(value) => return Ok(value),
+            Err(problem) => problem,
+        };
+
+        let retry = format!(
+            "Your previous response was invalid: {}. Respond with a corrected JSON object \
+             matching this schema: {}",
+            problem, pretty_schema
+        );
+        let raw = self.ask(&retry, None).await?;
+        validate_schema_object(&raw.content, schema).map_err(|problem| {
+            Error::Parse(format!(
+                "response does not match the JSON schema: {}",
+                problem
+            ))
+        })
+    }
+
+    /// Asks a question after a conversation history kept by the caller.
+    ///
+    /// `context` holds `(is_user, content)` pairs in order, e.g. loaded from
+    /// the caller's own storage. It is sent as a transcript prefix,
+    /// `"User: {msg}\nAssistant: {msg}\n..."`, followed by
+    /// `"User: {message}"`, so no server-side conversation is needed.
+    ///
+    /// # Errors
+    /// Returns `Error::Parse("context too long")` if `context` has more than
+    /// 20 turns.
+    pub async fn ask_with_explicit_context(
+        &mut self,
+        context: &[(bool, &str)],
+        message: &str,
+    ) -> Result<ChatResponse> {
+        if context.len() > MAX_EXPLICIT_CONTEXT_TURNS {
+            return Err(Error::Parse("context too long".to_string()));
+        }
+        let mut prompt = String::new();
+        for (is_user, content) in context {
+            let speaker = if *is_user { "User" } else { "Assistant" };
+            prompt.push_str(&format!("{}: {}\n", speaker, content.trim()));
+        }
+        prompt.push_str(&format!("User: {}", message));
+        self.ask(&prompt, None).await
+    }
+
+    /// Asks for code in a given language, with an explanation and tests.
+    ///
+    /// Sends `"Write {language} code that {spec}. Include a brief explanation
+    /// and 2-3 test cases."`. The first fenced code block becomes `code`, the
+    /// following blocks `test_cases`, and the prose around them
+    /// `explanation`.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if `language` is not a known programming
+    /// language, compared case-insensitively.
+    pub async fn ask_code_generation(
+        &mut self,
+        spec: &str,
+        language: &str,
+    ) -> Result<CodeGenerationResponse> {
+        let language = language.trim();
+        if !CODE_LANGUAGES.contains(&language.to_lowercase().as_str()) {
+            return Err(Error::InvalidInput(format!(
+                "unsupported language: {:?}",
+                language
+            )));
+        }
+        let prompt = format!(
+            "Write {} code that {}. Include a brief explanation and 2-3 test cases.",
+            language, spec
+        );
+        let raw = self.ask(&prompt, None).await?;
+
+        let (blocks, prose) = split_code_blocks(&raw.content);
+        let mut blocks = blocks.into_iter();
+        let code = blocks
+            .next()
+            .unwrap_or_else(|| raw.content.trim().to_string());
+        let explanation = Some(prose).filter(|p| !p.is_empty());
+
+        Ok(CodeGenerationResponse {
+            code,
+            language: language.to_string(),
+            explanation,
+            test_cases: blocks.collect(),
+            raw,
+        })
+    }
+
+    /// Asks for a structured analysis of an image.
+    ///
+    /// Requests JSON listing detected objects, any text in the image,
+    /// dominant colors and a one-sentence description. If the reply is not
+    /// valid JSON, fields are taken from `Objects:`, `Text:`, `Colors:` and
+    /// `Description:` lines instead, and the description falls back to the
+    /// first sentence of the reply.
+    ///
+    /// # Errors
+    /// Returns `Error::Upload` if `image` is not a supported image format.
+    pub async fn ask_with_image_analysis(&mut self, image: &[u8]) -> Result<ImageAnalysisResponse> {
+        if detect_image_mime(image).is_none() {
+            return Err(Error::Upload("Unsupported image format".to_string()));
+        }
+        let raw = self.ask(IMAGE_ANALYSIS_PROMPT, Some(image)).await?;
+        Ok(parse_image_analysis(raw))
+    }
+
+    /// Asks a question and requests a basis for each factual claim.
+    ///
+    /// Appends `"For each factual claim, provide the basis for it in [brackets]."`
+    /// to the message, then parses the bracketed annotations into citations.
+    /// URLs found in the response's factuality data are added as citations
+    /// with an empty claim.
+    pub async fn ask_with_citations_requested(&mut self, message: &str) -> Result<CitedResponse> {
+        let prompt = format!("{}\n\n{}", message, CITATION_INSTRUCTION);
+        let raw = self.ask(&prompt, None).await?;
+
+        let mut citations: Vec<Citation> = bracket_annotations(&raw.content)
+            .into_iter()
+            .map(|(start, _, hint)| Citation {
+                claim: preceding_sentence(&raw.content[..start]).to_string(),
+                source_hint: Some(hint.to_string()),
+            })
+            .collect();
+
+        if let Some(queries) = &raw.factuality_queries {
+            let mut urls = Vec::new();
+            collect_urls(queries, &mut urls);
+            for url in urls {
+                if !citations
+                    .iter()
+                    .any(|c| c.source_hint.as_deref() == Some(url.as_str()))
+                {
+                    citations.push(Citation {
+                        claim: String::new(),
+                        source_hint: Some(url),
+                    });
+                }
+            }
+        }
+
+        Ok(CitedResponse {
+            content: raw.content.clone(),
+            citations,
+            raw,
+        })
+    }
+
+    /// Asks for a response of at most roughly `max_words` words.
+    ///
+    /// Appends `"Respond in approximately {max_words} words or fewer."`. If
+    /// the reply is more than twice as long, the response is flagged with
+    /// `error: true` and a warning is appended to `content`.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if `max_words` is less than 10.
+    pub async fn ask_with_output_length_hint(
+        &mut self,
+        message: &str,
+        max_words: usize,
+    ) -> Result<ChatResponse> {
+        if max_words < 10 {
+            return Err(Error::InvalidInput(
+                "max_words must be at least 10".to_string(),
+            ));
+        }
+        let prompt = format!(
+            "{}\n\nRespond in approximately {} words or fewer.",
+            message, max_words
+        );
+        let mut response = self.ask(&prompt, None).await?;
+
+        let words = response.content.split_whitespace().count();
+        if words > max_words * 2 {
+            response.error = true;
+            response.content.push_str(&format!(
+                "\n\n[WARNING: response has {} words, length hint was {}]",
+                words, max_words
+            ));
+        }
+        Ok(response)
+    }
+
+    /// Asks for a response of at least roughly `min_words` words.
+    ///
+    /// Appends `"Respond in at least {min_words} words."`. If the reply is
+    /// less than half as long, the response is flagged with `error: true` and
+    /// a warning is appended to `content`.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if `min_words` is 0.
+    pub async fn ask_with_min_length_hint(
+        &mut self,
+        message: &str,
+        min_words: usize,
+    ) -> Result<ChatResponse> {
+        if min_words < 1 {
+            return Err(Error::InvalidInput(
+                "min_words must be at least 1".to_string(),
+            ));
+        }
+        let prompt = format!("{}\n\nRespond in at least {} words.", message, min_words);
+        let mut response = self.ask(&prompt, None).await?;
+
+        let words = response.content.split_whitespace().count();
+        if words * 2 < min_words {
+            response.error = true;
+            response.content.push_str(&format!(
+                "\n\n[WARNING: response has {} words, length hint was {}]",
+                words, min_words
+            ));
+        }
+        Ok(response)
+    }
+
+    /// Sends a message, splitting it into parts if the context would overflow.
+    ///
+    /// If [`estimated_context_size`](AsyncChatbot::estimated_context_size)
+    /// plus the message length stays within `max_context_chars`, this is a
+    /// plain [`ask`](AsyncChatbot::ask). Otherwise the message is split with
+    /// [`chunk_text`](crate::utils::chunk_text), each part is sent as
+    /// `"[Part N/M] ..."`, and a final prompt asks Gemini to respond to the
+    /// complete message.
+    ///
+    /// # Returns
+    /// The response to the final synthesis prompt.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if `max_context_chars` is 0.
+    pub async fn ask_with_context_window_check(
+        &mut self,
+        message: &str,
+        max_context_chars: usize,
+    ) -> Result<ChatResponse> {
+        if max_context_chars == 0 {
+            return Err(Error::InvalidInput(
+                "max_context_chars must be greater than 0".to_string(),
+            ));
+        }
+        if self.estimated_context_size() + message.chars().count() <= max_context_chars {
+            return self.ask(message, None).await;
+        }
+
+        let parts = chunk_text(message, max_context_chars);
+        let total = parts.len();
+        for (i, part) in parts.iter().enumerate() {
+            let prompt = format!(
+                "[Part {}/{}] This message is split into {} parts. Reply only with \"Received\" until all parts are sent.\n\n{}",
+                i + 1,
+                total,
+                total,
+                part
+            );
+            self.ask(&prompt, None).await?;
+        }
+
+        let synthesis = format!(
+            "All {} parts have been sent. Treat parts 1 to {} as one message and respond to it in full.",
+            total, total
+        );
+        self.ask(&synthesis, None).await
+    }
+
+    /// Asks Gemini to pick the best of several candidates.
+    ///
+    /// Sends the candidates as a numbered list followed by the question and
+    /// `"Select the best option by number and explain why."`. The first number
+    /// in the reply that matches a candidate is taken as the selection, and
+    /// the text after it as the reasoning.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` unless there are 2 to 20 candidates, and
+    /// `Error::Parse("could not extract selection")` if the reply names no
+    /// candidate number.
+    pub async fn ask_with_voting(
+        &mut self,
+        question: &str,
+        candidates: Vec<String>,
+    ) -> Result<VotingResponse> {
+        if !(2..=20).contains(&candidates.len()) {
+            return Err(Error::InvalidInput(
+                "between 2 and 20 candidates are required".to_string(),
+            ));
+        }
+
+        let numbered_list = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, candidate)| format!("{}. {}", i + 1, candidate))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let prompt = format!(
+            "Given the following options:\n{}\n\nQuestion: {}\n\nSelect the best option by number and explain why.",
+            numbered_list, question
+        );
+        let raw = self.ask(&prompt, None).await?;
+
+        static NUMBER: OnceLock<Regex> = OnceLock::new();
+        let (selected_index, end) = NUMBER
+            .get_or_init(|| Regex::new(r"\d+").unwrap())
+            .find_iter(&raw.content)
+            .find_map(|m| {
+                let number = m.as_str().parse::<usize>().ok()?;
+                (1..=candidates.len())
+                    .contains(&number)
+                    .then_some((number - 1, m.end()))
+            })
+            .ok_or_else(|| Error::Parse("could not extract selection".to_string()))?;
+
+        let after = raw.content[end..]
+            .trim_start_matches(|c: char| c.is_whitespace() || "*.:)-–—".contains(c));
+        let reasoning = if after.trim().is_empty() {
+            raw.content.trim().to_string()
+        } else {
+            after.trim().to_string()
+        };
+        let confidence = percent_confidence(&raw.content);
+
+        Ok(VotingResponse {
+            selected_index,
+            selected_value: candidates[selected_index].clone(),
+            reasoning,
+            confidence,
+            raw,
+        })
+    }
+
+    /// Answers a message, then has Gemini critique and improve its answer.
+    ///
+    /// Each of the `reflection_turns` rounds asks
+    /// `"Critique your previous answer. What are its weaknesses?"` followed by
+    /// `"Improve your answer based on the critique."` in the current
+    /// conversation. See [`ask_with_reflection_trace`](Self::ask_with_reflection_trace)
+    /// for every intermediate response.
+    ///
+    /// # Returns
+    /// The last improved answer, or the initial answer if `reflection_turns`
+    /// is 0.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if `reflection_turns` is above 3.
+    pub async fn ask_with_reflection(
+        &mut self,
+        message: &str,
+        reflection_turns: u8,
+    ) -> Result<ChatResponse> {
+        Ok(self
+            .ask_with_reflection_trace(message, reflection_turns)
+            .await?
+            .final_answer)
+    }
+
+    /// Like [`ask_with_reflection`](Self::ask_with_reflection), returning
+    /// the initial answer and every critique and improvement.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if `reflection_turns` is above 3.
+    pub async fn ask_with_reflection_trace(
+        &mut self,
+        message: &str,
+        reflection_turns: u8,
+    ) -> Result<ReflectionTrace> {
+        if reflection_turns > MAX_REFLECTION_TURNS {
+            return Err(Error::InvalidInput(format!(
+                "at most {} reflection turns are supported",
+                MAX_REFLECTION_TURNS
+            )));
+        }
+
+        let initial = self.ask(message, None).await?;
+        let mut reflections = Vec::with_capacity(reflection_turns as usize);
+        for _ in 0..reflection_turns {
+            let critique = self.ask(CRITIQUE_PROMPT, None).await?;
+            let improved = self.ask(IMPROVE_PROMPT, None).await?;
+            reflections.push((critique, improved));
+        }
+        let final_answer = reflections
+            .last()
+            .map_or_else(|| initial.clone(), |(_, improved)| improved.clone());
+
+        Ok(ReflectionTrace {
+            initial,
+            reflections,
+            final_answer,
+        })
+    }
+
+    /// Writes a long-form document by outlining it first.
+    ///
+    /// Asks for an outline, expands each top-level outline item in turn, and
+    /// finally asks `"Synthesize these sections into a coherent document."`.
+    /// All requests share the current conversation, so the synthesis step
+    /// sees every expanded section. If the outline has no list items, each
+    /// non-empty line is treated as a section.
+    ///
+    /// # Errors
+    /// Returns `Error::Parse` if the outline reply is empty.
+    pub async fn ask_with_outline_first(
+        &mut self,
+        topic: &str,
+        config: Option<OutlineFirstConfig>,
+    ) -> Result<LongFormResponse> {
+        let config = config.unwrap_or_default();
+        let outline_prompt = config.outline_prompt.replace("{topic}", topic);
+        let outline_reply = self.ask(&outline_prompt, None).await?;
+
+        let outline = parse_outline(&outline_reply.content);
+        if outline.is_empty() {
+            return Err(Error::Parse("outline reply was empty".to_string()));
+        }
+
+        let mut sections = Vec::with_capacity(outline.len());
+        for section in &outline {
+            let prompt = config
+                .expand_prompt_template
+                .replace("{topic}", topic)
+                .replace("{section}", section);
+            sections.push(self.ask(&prompt, None).await?.content);
+        }
+
+        let final_content = self
+            .ask("Synthesize these sections into a coherent document.", None)
+            .await?
+            .content;
+
+        Ok(LongFormResponse {
+            outline,
+            sections,
+            final_content,
+        })
+    }
+
+    /// Answers a question using only the given facts, e.g. retrieved snippets.
+    ///
+    /// The facts are sent as a numbered list after
+    /// `"Use ONLY the following facts to answer the question. Do not hallucinate."`,
+    /// followed by `"Question: {question}"`.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` unless there are 1 to 20 facts.
+    pub async fn ask_with_facts(&mut self, facts: &[&str], question: &str) -> Result<ChatResponse> {
+        let prompt = facts_prompt(facts, question)?;
+        self.ask(&prompt, None).await
+    }
+
+    /// Like [`ask_with_facts`](Self::ask_with_facts), but asks for
+    /// `'I don't know'` when the facts are insufficient and detects that reply.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` unless there are 1 to 20 facts.
+    pub async fn ask_with_facts_strict(
+        &mut self,
+        facts: &[&str],
+        question: &str,
+    ) -> Result<FactsResponse> {
+        let prompt = format!(
+            "{}\n\n{}",
+            facts_prompt(facts, question)?,
+            FACTS_STRICT_INSTRUCTION
+        );
+        let raw = self.ask(&prompt, None).await?;
+
+        let normalized = raw.content.to_lowercase().replace(['’', '`'], "'");
+        let insufficient_facts = ["i don't know", "i do not know"]
+            .iter()
+            .any(|phrase| normalized.contains(phrase));
+
+        Ok(FactsResponse {
+            content: raw.content.trim().to_string(),
+            insufficient_facts,
+            raw,
+        })
+    }
+
+    /// Compares two options with respect to a question.
+    ///
+    /// Sends `"Compare {option_a} vs {option_b} regarding: {question}. List pros
+    /// and cons for each and state your preference."` and parses pros/cons
+    /// list items under headings that name an option. If the reply does not
+    /// follow that shape, the full content is returned as `reasoning`.
+    pub async fn ask_compare(
+        &mut self,
+        question: &str,
+        option_a: &str,
+        option_b: &str,
+    ) -> Result<ComparisonResponse> {
+        let prompt = format!(
+            "Compare {} vs {} regarding: {}. List pros and cons for each and state your preference.",
+            option_a, option_b, question
+        );
+        let raw = self.ask(&prompt, None).await?;
+        Ok(parse_comparison(raw, option_a, option_b))
+    }
+
+    /// Translates `text` into `target_language`.
+    ///
+    /// The source language is detected locally with
+    /// [`detect_language`](crate::utils::detect_language).
+    ///
+    /// # Arguments
+    /// * `text` - Text to translate
+    /// * `target_language` - ISO 639-1 code of the target language (e.g. `"fr"`)
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if `target_language` is not an ISO 639-1 code.
+    pub async fn ask_translate(
+        &mut self,
+        text: &str,
+        target_language: &str,
+    ) -> Result<TranslationResponse> {
+        self.translate(text, None, target_language).await
+    }
+
+    /// Translates `text` from a known source language into `target_language`.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidInput` if either language is not an ISO 639-1 code.
+    pub async fn ask_translate_with_source(
+        &mut self,
+        text: &str,
+        source_language: &str,
+        target_language: &str,
+    ) -> Result<TranslationResponse> {
+        self.translate(text, Some(source_language), target_language)
+            .await
+    }
+
+    async fn translate(
+        &mut self,
+        text: &str,
+        source_language: Option<&str>,
+        target_language: &str,
+    ) -> Result<TranslationResponse> {
+        let target_name = language_name(target_language).ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "Unknown ISO 639-1 language code: {}",
+                target_language
+            ))
+        })?;
+
+        let (prompt, source) = match source_language {
+            Some(source) => {
+                let source_name = language_name(source).ok_or_else(|| {
+                    Error::InvalidInput(format!("Unknown ISO 639-1 language code: {}", source))
+                })?;
+                let prompt = format!(
+                    "Translate the following text from {} to {}. Output ONLY the translated text, nothing else:\n\n{}",
+                    source_name, target_name, text
+                );
+                (prompt, Some(source.to_lowercase()))
+            }
+            None => {
+                let prompt = format!(
+                    "Translate the following text to {}. Output ONLY the translated text, nothing else:\n\n{}",
+                    target_name, text
+                );
+                (prompt, detect_language(text))
+            }
+        };
+
+        let raw = self.ask(&prompt, None).await?;
+        Ok(TranslationResponse {
+            translated_text: raw.content.trim().to_string(),
+            detected_source_language: source,
+            raw,
+        })
+    }
+
+    /// Asks a question with step-by-step reasoning instructions.
+    ///
+    /// Appends a request to reason step by step and finish with a
+    /// `Final answer: ...` line. Numbered and bulleted lines become `steps`.
+    /// If no steps are found, the whole content becomes a single step; if no
+    /// final answer line is found, the last sentence is used instead.
+    pub async fn ask_with_step_by_step(&mut self, message: &str) -> Result<StepByStepResponse> {
+        let prompt = format!("{}\n\n{}", message, STEP_BY_STEP_INSTRUCTION);
+        let raw = self.ask(&prompt, None).await?;
+
+        let mut steps = Vec::new();
+        let mut final_answer = None;
+        for line in raw.content.lines() {
+            let line = line.trim().trim_matches('*').trim();
+            if let Some(answer) = strip_prefix_ignore_case(line, "final answer:") {
+                final_answer = Some(answer.trim_start_matches('*').trim().to_string());
+            } else if let Some(step) = strip_list_marker(line) {
+                steps.push(step.to_string());
+            }
+        }
+
+        if steps.is_empty() {
+            steps.push(raw.content.trim().to_string());
+        }
+        let final_answer = final_answer.unwrap_or_else(|| last_sentence(&raw.content).to_string());
+
+        Ok(StepByStepResponse {
+            steps,
+            final_answer,
+            raw,
+        })
+    }
+}
+
+/// Extracts and validates the formatted output of a response.
+fn formatted_response(raw: ChatResponse, kind: FormatKind) -> FormattedResponse {
+    let content = match kind {
+        FormatKind::PlainText | FormatKind::Markdown => raw.content.trim().to_string(),
+        _ => fenced_block(&raw.content)
+            .unwrap_or(&raw.content)
+            .trim()
+            .to_string(),
+    };
+    let parse_success = !content.is_empty()
+        && match kind {
+            FormatKind::PlainText => markdown_to_plain_text(&content, true).trim() == content,
+            FormatKind::Markdown => true,
+            FormatKind::Json => serde_json::from_str::<Value>(&content).is_ok(),
+            FormatKind::Yaml => is_valid_yaml(&content),
+            FormatKind::Csv => is_valid_csv(&content),
+            FormatKind::HtmlTable => {
+                let lower = content.to_lowercase();
+                lower
+                    .find("<table")
+                    .is_some_and(|start| lower[start..].contains("</table>"))
+            }
+        };
+    FormattedResponse {
+        content,
+        format: kind,
+        parse_success,
+        raw,
+    }
+}
+
+/// Parses a reply as a JSON object with the schema's required keys.
+///
+/// Returns a description of the first problem found.
+fn validate_schema_object(content: &str, schema: &Value) -> std::result::Result<Value, String> {
+    let json = fenced_block(content).unwrap_or(content).trim();
+    let value: Value =
+        serde_json::from_str(json).map_err(|e| format!("it is not valid JSON ({})", e))?;
+    let Some(object) = value.as_object() else {
+        return Err("it is not a JSON object".to_string());
+    };
+    let required = schema.get("required").and_then(Value::as_array);
+    let missing: Vec<&str> = required
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .filter(|key| !object.contains_key(*key))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("missing required keys: {}", missing.join(", ")));
+    }
+    Ok(value)
+}
+
+/// Builds an [`ImageAnalysisResponse`] from JSON or labelled prose.
+fn parse_image_analysis(raw: ChatResponse) -> ImageAnalysisResponse {
+    let content = raw.content.as_str();
+    let json = fenced_block(content).unwrap_or(content);
+    let json = match (json.find('{'), json.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &json[start..=end],
+        _ => json,
+    };
+
+    if let Ok(Value::Object(map)) = serde_json::from_str::<Value>(json) {
+        let strings = |key: &str| -> Vec<String> {
+            map.get(key)
+                .and_then(Value::as_array)
+                .map(|items| {
+                    items
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let text_extracted = map
+            .get("text_extracted")
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+            .map(str::to_string);
+        let scene_description = map
+            .get("scene_description")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        return ImageAnalysisResponse {
+            objects_detected: strings("objects_detected"),
+            text_extracted,
+            dominant_colors: strings("dominant_colors"),
+            scene_description,
+            raw,
+        };
+    }
+
+    // Prose fallback: labelled lines, else the first sentence
+    let mut objects_detected = Vec::new();
+    let mut text_extracted = None;
+    let mut dominant_colors = Vec::new();
+    let mut scene_description = None;
+    for line in content.lines() {
+        let line = line
+            .trim()
+            .trim_start_matches(['*', '-', ' '])
+            .replace("**", "");
+        let split_list = |value: &str| -> Vec<String> {
+            value
+                .split(',')
+                .map(|item| item.trim().trim_end_matches('.').to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        };
+        if let Some(value) = strip_prefix_ignore_case(&line, "objects:") {
+            objects_detected = split_list(value);
+        } else if let Some(value) = strip_prefix_ignore_case(&line, "colors:") {
+            dominant_colors = split_list(value);
+        } else if let Some(value) = strip_prefix_ignore_case(&line, "text:") {
+            let value = value.trim();
+            if !value.is_empty() && !value.eq_ignore_ascii_case("none") {
+                text_extracted = Some(value.to_string());
+            }
+        } else if let Some(value) = strip_prefix_ignore_case(&line, "description:") {
+            scene_description = Some(value.trim().to_string());
+        }
+    }
+    let scene_description =
+        scene_description.unwrap_or_else(|| first_sentence(content).to_string());
+
+    ImageAnalysisResponse {
+        objects_detected,
+        text_extracted,
+        dominant_colors,
+        scene_description,
+        raw,
+    }
+}
+
+/// Splits Markdown into fenced code block bodies and the remaining prose.
+///
+/// An unterminated block runs to the end of the text. Empty blocks are
+/// skipped; prose paragraphs are joined with blank lines.
+fn split_code_blocks(text: &str) -> (Vec<String>, String) {
+    let mut blocks = Vec::new();
+    let mut prose: Vec<&str> = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    for line in text.lines() {
+        let is_fence = line.trim_start().starts_with("```");
+        match current.as_mut() {
+            Some(body) if is_fence => {
+                let body = body.join("\n");
+                if !body.trim().is_empty() {
+                    blocks.push(body);
+                }
+                current = None;
+            }
+            Some(body) => body.push(line),
+            None if is_fence => current = Some(Vec::new()),
+            None => prose.push(line),
+        }
+    }
+    if let Some(body) = current {
+        let body = body.join("\n");
+        if !body.trim().is_empty() {
+            blocks.push(body);
+        }
+    }
+
+    let prose = prose
+        .join("\n")
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    (blocks, prose)
+}
+
+/// Returns the contents of the first fenced code block, if any.
+fn fenced_block(text: &str) -> Option<&str> {
+    let start = text.find("```")?;
+    let after = &text[start + 3..];
+    // Skip the info string, e.g. `json`
+    let body = &after[after.find('\n')? + 1..];
+    let end = body.find("```").unwrap_or(body.len());
+    Some(&body[..end])
+}
+
+/// Checks that text is shaped like a block-style YAML document.
+///
+/// Every line must be a comment, a `key: value` pair, a `- item`, a document
+/// marker, or a continuation indented under a previous line. Tabs in the
+/// indentation are rejected, as YAML forbids them. Flow-style documents are
+/// accepted if they parse as JSON.
+fn is_valid_yaml(text: &str) -> bool {
+    if serde_json::from_str::<Value>(text).is_ok() {
+        return true;
+    }
+    let mut structured = false;
+    // Indentation of the last pair or item; continuations must be deeper
+    let mut parent_indent: Option<usize> = None;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed == "---" || trimmed == "..." {
+            continue;
+        }
+        let indentation = &line[..line.len() - line.trim_start().len()];
+        if indentation.contains('\t') {
+            return false;
+        }
+        let indent = indentation.len();
+        let is_item = trimmed == "-" || trimmed.starts_with("- ");
+        let is_pair = trimmed.split_once(':').is_some_and(|(key, rest)| {
+            !key.trim().is_empty() && (rest.is_empty() || rest.starts_with(' '))
+        });
+        if is_item || is_pair {
+            structured = true;
+            parent_indent = Some(indent);
+        } else if parent_indent.is_none_or(|parent| indent <= parent) {
+            return false;
+        }
+    }
+    structured
+}
+
+/// Checks that text is CSV whose records all have the same number of fields.
+///
+/// Quoted fields may contain commas, doubled quotes and line breaks.
+fn is_valid_csv(text: &str) -> bool {
+    let mut counts = Vec::new();
+    let mut fields = 1;
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+    let mut line_empty = true;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields += 1,
+            '\n' if !in_quotes => {
+                if !line_empty {
+                    counts.push(fields);
+                }
+                fields = 1;
+                line_empty = true;
+                continue;
+            }
+            '\r' if !in_quotes => continue,
+            _ => {}
+        }
+        line_empty = false;
+    }
+    if in_quotes {
+        return false;
+    }
+    if !line_empty {
+        counts.push(fields);
+    }
+    counts
+        .first()
+        .is_some_and(|first| counts.iter().all(|n| n == first))
+}
+
+/// Builds the prompt shared by the facts workflows.
+fn facts_prompt(facts: &[&str], question: &str) -> Result<String> {
+    if facts.is_empty() || facts.len() > 20 {
+        return Err(Error::InvalidInput(
+            "between 1 and 20 facts are required".to_string(),
+        ));
+    }
+    let numbered = facts
+        .iter()
+        .enumerate()
+        .map(|(i, fact)| format!("{}. {}", i + 1, fact.trim()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(format!(
+        "{}{}\n\nQuestion: {}",
+        FACTS_INSTRUCTION, numbered, question
+    ))
+}
+
+/// Parses a comparison reply into pros, cons and a preference.
+fn parse_comparison(raw: ChatResponse, option_a: &str, option_b: &str) -> ComparisonResponse {
+    let a = option_a.to_lowercase();
+    let b = option_b.to_lowercase();
+
+    let mut lists: [Vec<String>; 4] = Default::default();
+    let mut current: Option<usize> = None;
+    let mut option_context: Option<bool> = None;
+    let mut preference_line: Option<&str> = None;
+
+    for line in raw.content.lines() {
+        if let Some(item) = strip_list_marker(line) {
+            if let Some(index) = current {
+                lists[index].push(item.trim_matches('*').trim().to_string());
+                continue;
+            }
+        }
+
+        let lower = line.to_lowercase();
+        let mentions_a = lower.contains(&a);
+        let mentions_b = lower.contains(&b);
+        if mentions_a != mentions_b {
+            option_context = Some(mentions_a);
+        }
+
+        let has_word = |words: &[&str]| {
+            lower
+                .split(|c: char| !c.is_alphanumeric())
+                .any(|w| words.contains(&w))
+        };
+
+        if lower.contains("prefer") || lower.contains("recommend") || lower.contains("verdict") {
+            preference_line = Some(line.trim());
+            current = None;
+        } else if has_word(&["pros", "advantages", "strengths"]) {
+            current = option_context.map(|is_a| if is_a { 0 } else { 2 });
+        } else if has_word(&["cons", "disadvantages", "weaknesses"]) {
+            current = option_context.map(|is_a| if is_a { 1 } else { 3 });
+        } else if strip_list_marker(line).is_none() && !line.trim().is_empty() {
+            current = None;
+        }
+    }
+
+    let preference = preference_line.and_then(|line| {
+        let lower = line.to_lowercase();
+        let chosen = match (lower.find(&a), lower.find(&b)) {
+            (Some(_), None) => option_a,
+            (None, Some(_)) => option_b,
+            (Some(pos_a), Some(pos_b)) if pos_a <= pos_b => option_a,
+            (Some(_), Some(_)) => option_b,
+            (None, None) => return None,
+        };
+        let confidence = percent_confidence(line).unwrap_or(0.7);
+        Some(ComparisonPreference {
+            chosen: chosen.to_string(),
+            confidence,
+        })
+    });
+
+    let [pros_a, cons_a, pros_b, cons_b] = lists;
+    let structured = preference.is_some()
+        || [&pros_a, &cons_a, &pros_b, &cons_b]
+            .iter()
+            .any(|list| !list.is_empty());
+    let reasoning = match preference_line {
+        Some(line) if structured => line.trim_matches('*').trim().to_string(),
+        _ => raw.content.clone(),
+    };
+
+    ComparisonResponse {
+        preference,
+        reasoning,
+        pros_a,
+        cons_a,
+        pros_b,
+        cons_b,
+        raw,
+    }
+}
+
+/// Strips a case-insensitive ASCII prefix.
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = text.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &text[prefix.len()..])
+}
+
+/// Extracts the least-indented list items of an outline.
+///
+/// Falls back to every non-empty line when there are no list items.
+fn parse_outline(content: &str) -> Vec<String> {
+    let items: Vec<(usize, &str)> = content
+        .lines()
+        .filter_map(|line| {
+            let indent = line.len() - line.trim_start().len();
+            strip_list_marker(line).map(|item| (indent, item))
+        })
+        .collect();
+
+    let clean = |item: &str| item.trim_matches('*').trim().to_string();
+    match items.iter().map(|(indent, _)| *indent).min() {
+        Some(top) => items
+            .into_iter()
+            .filter(|(indent, _)| *indent == top)
+            .map(|(_, item)| clean(item))
+            .filter(|item| !item.is_empty())
+            .collect(),
+        None => content
+            .lines()
+            .map(|line| clean(line.trim_start_matches('#')))
+            .filter(|line| !line.is_empty())
+            .collect(),
+    }
+}
+
+/// Strips a numbered (`1.`, `2)`) or bulleted (`-`, `*`, `•`) list marker.
+pub(crate) fn strip_list_marker(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    for bullet in ["- ", "* ", "• ", "+ "] {
+        if let Some(rest) = line.strip_prefix(bullet) {
+            return Some(rest.trim());
+        }
+    }
+
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    let rest = &line[digits..];
+    rest.strip_prefix(". ")
+        .or_else(|| rest.strip_prefix(") "))
+        .map(str::trim)
+}
+
+/// Byte offsets just past each sentence-ending `.`, `?` or `!` that is
+/// followed by whitespace or the end of the text.
+fn sentence_ends(text: &str) -> impl Iterator<Item = usize> + '_ {
+    text.char_indices().filter_map(move |(i, c)| {
+        let end = i + c.len_utf8();
+        let at_boundary = text[end..].chars().next().is_none_or(char::is_whitespace);
+        (matches!(c, '.' | '?' | '!') && at_boundary).then_some(end)
+    })
+}
+
+/// Returns the first sentence of `text`, including its terminator.
+fn first_sentence(text: &str) -> &str {
+    let text = text.trim();
+    let end = text
+        .char_indices()
+        .find(|&(_, c)| matches!(c, '.' | '!' | '?' | '\n'))
+        .map(|(i, c)| i + c.len_utf8())
+        .unwrap_or(text.len());
+    text[..end].trim()
+}
+
+/// Returns the last sentence of `text`.
+fn last_sentence(text: &str) -> &str {
+    let text = text.trim();
+    let body = text.trim_end_matches(['.', '!', '?']);
+    let start = body
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| matches!(c, '.' | '!' | '?' | '\n'))
+        .map(|(i, c)| i + c.len_utf8())
+        .unwrap_or(0);
+    text[start..].trim()
+}
+
+/// Reads the first percentage in `text`, e.g. "90%", as a confidence in 0..=1.
+fn percent_confidence(text: &str) -> Option<f32> {
+    static PERCENT: OnceLock<Regex> = OnceLock::new();
+    PERCENT
+        .get_or_init(|| Regex::new(r"(\d{1,3})\s*%").unwrap())
+        .captures(text)
+        .and_then(|caps| caps[1].parse::<f32>().ok())
+        .map(|pct| (pct / 100.0).clamp(0.0, 1.0))
+}
+
+/// Finds `[...]` annotations that are not Markdown links.
+///
+/// Returns `(start, end, inner_text)` byte ranges.
+fn bracket_annotations(text: &str) -> Vec<(usize, usize, &str)> {
+    static ANNOTATION: OnceLock<Regex> = OnceLock::new();
+    ANNOTATION
+        .get_or_init(|| Regex::new(r"\[([^\[\]\n]+)\]").unwrap())
+        .captures_iter(text)
+        .filter_map(|caps| {
+            let whole = caps.get(0)?;
+            if text[whole.end()..].starts_with('(') {
+                return None;
+            }
+            Some((whole.start(), whole.end(), caps.get(1)?.as_str().trim()))
+        })
+        .collect()
+}
+
+/// Returns the last sentence of `text`, without its terminator.
+fn preceding_sentence(text: &str) -> &str {
+    let text = text.trim_end().trim_end_matches(['.', '!', '?']);
+    let start = text
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| matches!(c, '.' | '!' | '?' | '\n' | ']'))
+        .map(|(i, c)| i + c.len_utf8())
+        .unwrap_or(0);
+    text[start..].trim()
+}
+
+/// Recursively collects http(s) URLs from a JSON value.
+fn collect_urls(value: &Value, urls: &mut Vec<String>) {
+    match value {
+        Value::String(s)
+            if (s.starts_with("http://") || s.starts_with("https://")) && !urls.contains(s) =>
+        {
+            urls.push(s.clone());
+        }
+        Value::Array(items) => items.iter().for_each(|v| collect_urls(v, urls)),
+        Value::Object(map) => map.values().for_each(|v| collect_urls(v, urls)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        client, generate_reply, sent_prompt, MockResponse, MockServer, GENERATE_PATH,
+    };
+
+    /// A server answering every Generate request with `reply`.
+    async fn reply_server(reply: &str) -> MockServer {
+        MockServer::routes(vec![(
+            GENERATE_PATH,
+            vec![MockResponse::ok(generate_reply(
+                "c_1",
+                "r_1",
+                &[("rc_1", reply)],
+            ))],
+        )])
+        .await
+    }
+
+    /// `count` numbered strings starting with `prefix`.
+    fn numbered(prefix: &str, count: usize) -> Vec<String> {
+        (1..=count).map(|i| format!("{} {}", prefix, i)).collect()
+    }
+
+    #[tokio::test]
+    async fn explicit_context_accepts_at_most_20_turns() {
+        let server = reply_server("Noted.").await;
+        let mut chatbot = client(&server).await;
+        let messages = numbered("Turn", 21);
+        let context: Vec<(bool, &str)> = messages
+            .iter()
+            .enumerate()
+            .map(|(i, message)| (i % 2 == 0, message.as_str()))
+            .collect();
+
+        chatbot
+            .ask_with_explicit_context(&context[..20], "And now?")
+            .await
+            .unwrap();
+        let prompt = sent_prompt(&server.requests_to(GENERATE_PATH)[0]);
+        assert!(prompt.starts_with("User: Turn 1\nAssistant: Turn 2\n"));
+        assert!(prompt.ends_with("Assistant: Turn 20\nUser: And now?"));
+
+        let error = chatbot
+            .ask_with_explicit_context(&context, "And now?")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, Error::Parse(message) if message == "context too long"),
+            "{:?}",
+            error
+        );
+        assert_eq!(server.requests_to(GENERATE_PATH).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn voting_accepts_2_to_20_candidates() {
+        let server = reply_server("2. It is the clearest option.").await;
+        let mut chatbot = client(&server).await;
+        for count in [2, 20] {
+            let vote = chatbot
+                .ask_with_voting("Which is best?", numbered("Option", count))
+                .await
+                .unwrap();
+            assert_eq!(vote.selected_index, 1);
+            assert_eq!(vote.selected_value, "Option 2");
+        }
+        let last = sent_prompt(server.requests_to(GENERATE_PATH).last().unwrap());
+        assert!(last.contains("\n20. Option 20\n"), "{}", last);
+
+        for count in [0, 1, 21] {
+            let error = chatbot
+                .ask_with_voting("Which is best?", numbered("Option", count))
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(error, Error::InvalidInput(_)),
+                "{}: {:?}",
+                count,
+                error
+            );
+        }
+        assert_eq!(server.requests_to(GENERATE_PATH).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn translation_accepts_iso_639_1_codes_only() {
+        let server = reply_server("Bonjour").await;
+        let mut chatbot = client(&server).await;
+        for code in ["fr", "FR", "zu"] {
+            let translation = chatbot.ask_translate("Hello", code).await.unwrap();
+            assert_eq!(translation.translated_text, "Bonjour");
+        }
+        let translation = chatbot
+            .ask_translate_with_source("Hello", "EN", "fr")
+            .await
+            .unwrap();
+        assert_eq!(translation.detected_source_language.as_deref(), Some("en"));
+        assert!(
+            sent_prompt(server.requests_to(GENERATE_PATH).last().unwrap())
+                .starts_with("Translate the following text from English to French.")
+        );
+
+        for code in ["", "f", "fra", "french", "xx", "fr-FR"] {
+            let error = chatbot.ask_translate("Hello", code).await.unwrap_err();
+            assert!(
+                matches!(error, Error::InvalidInput(_)),
+                "{:?}: {:?}",
+                code,
+                error
+            );
+        }
+        let error = chatbot
+            .ask_translate_with_source("Hello", "eng", "fr")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+        assert_eq!(server.requests_to(GENERATE_PATH).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn facts_accept_1_to_20_entries() {
+        let server = reply_server("Paris.").await;
+        let mut chatbot = client(&server).await;
+        let facts = numbered("Fact", 21);
+        let facts: Vec<&str> = facts.iter().map(String::as_str).collect();
+
+        for count in [1, 20] {
+            chatbot
+                .ask_with_facts(&facts[..count], "Where?")
+                .await
+                .unwrap();
+            chatbot
+                .ask_with_facts_strict(&facts[..count], "Where?")
+                .await
+                .unwrap();
+        }
+        let last = sent_prompt(server.requests_to(GENERATE_PATH).last().unwrap());
+        assert!(last.contains("\n20. Fact 20\n"), "{}", last);
+
+        for count in [0, 21] {
+            let error = chatbot
+                .ask_with_facts(&facts[..count], "Where?")
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(error, Error::InvalidInput(_)),
+                "{}: {:?}",
+                count,
+                error
+            );
+            let error = chatbot
+                .ask_with_facts_strict(&facts[..count], "Where?")
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(error, Error::InvalidInput(_)),
+                "{}: {:?}",
+                count,
+                error
+            );
+        }
+        assert_eq!(server.requests_to(GENERATE_PATH).len(), 4);
+    }
+}
@@ -0,0 +1,371 @@
+//! Requests and replies of the web app's batchexecute endpoint.
+//!
+//! Besides StreamGenerate, the web app reads and manages conversations
+//! through `batchexecute`, which carries one or more RPCs identified by short
+//! opaque IDs. A request sends `f.req=[[[rpc_id, payload, null, "generic"]]]`,
+//! where `payload` is the RPC's JSON arguments encoded as a string, and the
+//! reply holds one `["wrb.fr", rpc_id, payload, ...]` frame per RPC in the
+//! same length-prefixed format as StreamGenerate.
+
+use crate::client::{
+    ConversationMetadata, RequestParts, Role, Turn, TurnAttachment, GENERATE_BUILD_LABEL,
+    MODEL_NAME_FIELD, TRANSIENT_ERROR_CODE,
+};
+use crate::error::{Error, Result};
+use crate::utils::body_snippet;
+
+use serde_json::{json, Value};
+
+/// RPC reading the turns of a conversation.
+pub(crate) const READ_CHAT: &str = "hNvQHb";
+
+/// RPC reading a publicly shared conversation.
+pub(crate) const READ_SHARE: &str = "ujx1Bf";
+
+/// Number of turns requested when only the conversation's metadata is needed.
+const METADATA_TURN_LIMIT: u64 = 1000;
+
+/// Builds a batchexecute request for a single RPC.
+///
+/// The payload goes through `serde_json` twice, like the StreamGenerate
+/// `f.req` field, so any string inside it is escaped once per layer. An
+/// empty `snlm0e` leaves out the `at` field, as for signed-out requests.
+pub(crate) fn batch_request(
+    rpc_id: &str,
+    payload: &Value,
+    snlm0e: &str,
+    reqid: u32,
+) -> Result<RequestParts> {
+    let freq = json!([[[rpc_id, serde_json::to_string(payload)?, null, "generic"]]]);
+    Ok(RequestParts {
+        query: vec![
+            ("rpcids", rpc_id.to_string()),
+            ("source-path", "/app".to_string()),
+            ("bl", GENERATE_BUILD_LABEL.to_string()),
+            ("_reqid", reqid.to_string()),
+            ("rt", "c".to_string()),
+        ],
+        form: std::iter::once(("f.req", serde_json::to_string(&freq)?))
+            .chain((!snlm0e.is_empty()).then(|| ("at", snlm0e.to_string())))
+            .collect(),
+        headers: Default::default(),
+    })
+}
+
+/// The `rpc_id` frame of a batchexecute reply.
+enum Frame {
+    /// The RPC's payload, still encoded as a JSON string.
+    Payload(String),
+    /// The RPC failed, with Google's error code if the frame has one.
+    Failed(Option<u64>),
+}
+
+/// Finds the `rpc_id` frame of a batchexecute reply.
+fn find_frame(text: &str, rpc_id: &str) -> Option<Frame> {
+    for line in text.lines() {
+        let line = line.trim();
+        let line = line.strip_prefix(")]}'").unwrap_or(line).trim();
+        if !line.starts_with('[') {
+            continue;
+        }
+        let Ok(Value::Array(parts)) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        for part in &parts {
+            if part.get(0).and_then(Value::as_str) != Some("wrb.fr")
+                || part.get(1).and_then(Value::as_str) != Some(rpc_id)
+            {
+                continue;
+            }
+            if let Some(payload) = part.get(2).and_then(Value::as_str) {
+                return Some(Frame::Payload(payload.to_string()));
+            }
+            let code = part
+                .get(5)
+                .and_then(|status| status.get(2)?.get(0)?.get(1)?.get(0)?.as_u64());
+            return Some(Frame::Failed(code));
+        }
+    }
+    None
+}
+
+/// Returns the decoded payload of the `rpc_id` frame of a batchexecute reply.
+///
+/// # Errors
+/// Returns `Error::Transient` if the RPC failed with Google's transient
+/// error code, and `Error::Parse` if it failed otherwise or the reply has no
+/// frame for it.
+pub(crate) fn parse_batch_reply(text: &str, rpc_id: &str) -> Result<Value> {
+    match find_frame(text, rpc_id) {
+        Some(Frame::Payload(payload)) => serde_json::from_str(&payload)
+            .map_err(|e| Error::Parse(format!("Malformed {} payload: {}", rpc_id, e))),
+        Some(Frame::Failed(Some(code))) if code == TRANSIENT_ERROR_CODE => {
+            Err(Error::Transient { code })
+        }
+        Some(Frame::Failed(Some(code))) => Err(Error::Parse(format!(
+            "{} failed with error code {}",
+            rpc_id, code
+        ))),
+        Some(Frame::Failed(None)) => Err(Error::Parse(format!("{} returned no payload", rpc_id))),
+        None => Err(Error::Parse(format!(
+            "No {} frame in batchexecute reply. Content: {}",
+            rpc_id,
+            body_snippet(text)
+        ))),
+    }
+}
+
+/// Payload of a [`READ_CHAT`] request for the turns of `conversation_id`.
+pub(crate) fn read_chat_payload(conversation_id: &str) -> Value {
+    json!([
+        conversation_id,
+        METADATA_TURN_LIMIT,
+        null,
+        1,
+        [1],
+        [4],
+        null,
+        1
+    ])
+}
+
+/// Extracts conversation metadata from a [`READ_CHAT`] payload.
+///
+/// The payload starts with the list of turns, newest first. Each turn is
+/// `[[cid, rid], [cid, rid, rcid], [[prompt], ...], [[candidate, ...], ...],
+/// [seconds, nanos], ...]`, where a candidate is `[rcid, [text], ...]` and,
+/// in recent replies, names its model at index 21. The payload carries no
+/// title, so `title` stays `None`.
+pub(crate) fn parse_conversation_metadata(payload: &Value) -> ConversationMetadata {
+    let turns = payload.get(0).and_then(Value::as_array);
+    let timestamps: Vec<u64> = turns
+        .into_iter()
+        .flatten()
+        .filter_map(|turn| turn.get(4)?.get(0)?.as_u64())
+        .collect();
+    // Turns are listed newest first
+    let model = turns.into_iter().flatten().find_map(|turn| {
+        turn.get(3)?
+            .get(0)?
+            .get(0)?
+            .get(MODEL_NAME_FIELD)?
+            .as_str()
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+    });
+    ConversationMetadata {
+        title: None,
+        created: timestamps.iter().min().copied(),
+        updated: timestamps.iter().max().copied(),
+        turn_count: turns.map(Vec::len),
+        model,
+    }
+}
+
+/// Payload of a [`READ_SHARE`] request for the share `share_id`.
+pub(crate) fn read_share_payload(share_id: &str) -> Value {
+    json!([null, share_id, [4]])
+}
+
+/// Extracts the turns of a shared conversation from a [`READ_SHARE`] reply.
+///
+/// The payload is `[[share_id, title, ...], turns]`, with turns shaped as in
+/// [`parse_conversation_metadata`] and listed newest first. The turns are
+/// returned oldest first, alternating user and assistant. Images shown in an
+/// answer are attachments referenced by URL: web images are
+/// `candidate[12][1][i][0][0][0]` and generated images
+/// `candidate[12][7][0][i][0][3][3]`.
+///
+/// # Errors
+/// Returns `Error::ShareUnavailable` if the RPC failed or the payload has no
+/// turns, which is how revoked and private shares are answered,
+/// `Error::Transient` on Google's transient error code, and `Error::Parse`
+/// if the reply has no frame for the RPC.
+pub(crate) fn parse_share_reply(text: &str, share_id: &str) -> Result<Vec<Turn>> {
+    let unavailable = || Error::ShareUnavailable(share_id.to_string());
+    let payload: Value = match find_frame(text, READ_SHARE) {
+        Some(Frame::Payload(payload)) => serde_json::from_str(&payload)
+            .map_err(|e| Error::Parse(format!("Malformed {} payload: {}", READ_SHARE, e)))?,
+        Some(Frame::Failed(Some(code))) if code == TRANSIENT_ERROR_CODE => {
+            return Err(Error::Transient { code })
+        }
+        Some(Frame::Failed(_)) => return Err(unavailable()),
+        None => {
+            return Err(Error::Parse(format!(
+                "No {} frame in batchexecute reply. Content: {}",
+                READ_SHARE,
+                body_snippet(text)
+            )))
+        }
+    };
+    let turns = payload
+        .get(1)
+        .and_then(Value::as_array)
+        .filter(|turns| !turns.is_empty())
+        .ok_or_else(unavailable)?;
+
+    let mut transcript = Vec::with_capacity(turns.len() * 2);
+    for turn in turns.iter().rev() {
+        let prompt = turn.get(2).and_then(|p| p.get(0)?.get(0)?.as_str());
+        let candidate = turn.get(3).and_then(|c| c.get(0)?.get(0));
+        let answer = candidate.and_then(|c| c.get(1)?.get(0)?.as_str());
+        let (Some(prompt), Some(answer)) = (prompt, answer) else {
+            return Err(Error::Parse(format!(
+                "Malformed turn in shared conversation {}",
+                share_id
+            )));
+        };
+        transcript.push(Turn {
+            role: Role::User,
+            content: prompt.to_string(),
+            attachments: Vec::new(),
+        });
+        transcript.push(Turn {
+            role: Role::Assistant,
+            content: answer.to_string(),
+            attachments: candidate
+                .map(image_urls)
+                .unwrap_or_default()
+                .into_iter()
+                .map(TurnAttachment::linked)
+                .collect(),
+        });
+    }
+    Ok(transcript)
+}
+
+/// URLs of the web and generated images of a reply candidate.
+pub(crate) fn image_urls(candidate: &Value) -> Vec<String> {
+    let images = candidate.get(12);
+    let web = images
+        .and_then(|i| i.get(1)?.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|image| image.get(0)?.get(0)?.get(0)?.as_str());
+    let generated = images
+        .and_then(|i| i.get(7)?.get(0)?.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|image| image.get(0)?.get(3)?.get(3)?.as_str());
+    web.chain(generated).map(str::to_string).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_request_double_encodes_the_payload() {
+        let parts = batch_request(READ_CHAT, &json!(["c_1", "a \"quote\""]), "token", 42).unwrap();
+        assert_eq!(
+            parts.form[0].1,
+            r#"[[["hNvQHb","[\"c_1\",\"a \\\"quote\\\"\"]",null,"generic"]]]"#
+        );
+        assert_eq!(parts.form[1], ("at", "token".to_string()));
+        assert_eq!(parts.query[0], ("rpcids", "hNvQHb".to_string()));
+        assert_eq!(parts.query[3], ("_reqid", "42".to_string()));
+    }
+
+    #[test]
+    fn parse_batch_reply_frames() {
+        let reply = ")]}'\n\n51\n[[\"wrb.fr\",\"other\",\"[1]\",null,null,null,\"generic\"]]\n\
+                     58\n[[\"wrb.fr\",\"hNvQHb\",\"[[],null]\",null,null,null,\"generic\"]]\n";
+        assert_eq!(
+            parse_batch_reply(reply, READ_CHAT).unwrap(),
+            json!([[], null])
+        );
+
+        let failed = ")]}'\n[[\"wrb.fr\",\"hNvQHb\",null,null,null,[3,null,[[\"type\",[1013]]]]]]";
+        assert!(matches!(
+            parse_batch_reply(failed, READ_CHAT),
+            Err(Error::Transient { code: 1013 })
+        ));
+        let failed = ")]}'\n[[\"wrb.fr\",\"hNvQHb\",null,null,null,[3,null,[[\"type\",[7]]]]]]";
+        let error = parse_batch_reply(failed, READ_CHAT).unwrap_err();
+        assert!(error.to_string().contains("error code 7"), "{}", error);
+
+        let error = parse_batch_reply(")]}'\n[[\"di\",12]]", READ_CHAT).unwrap_err();
+        assert!(matches!(error, Error::Parse(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn metadata_from_read_chat_payload() {
+        let payload: Value =
+            serde_json::from_str(include_str!("../testdata/rpc/read_chat.json")).unwrap();
+        let metadata = parse_conversation_metadata(&payload);
+        assert_eq!(metadata.title, None);
+        assert_eq!(metadata.turn_count, Some(3));
+        assert_eq!(metadata.created, Some(1_717_000_000));
+        assert_eq!(metadata.updated, Some(1_717_000_600));
+        assert_eq!(metadata.model.as_deref(), Some("2.5 Pro"));
+
+        let empty = parse_conversation_metadata(&json!(null));
+        assert_eq!(empty.turn_count, None);
+        assert_eq!(empty.created, None);
+    }
+
+    #[test]
+    fn turns_from_read_share_reply() {
+        let payload: Value =
+            serde_json::from_str(include_str!("../testdata/rpc/read_share.json")).unwrap();
+        let reply = crate::test_support::batch_reply(READ_SHARE, &payload);
+        let turns = parse_share_reply(&reply, "a1B2c3D4e5F6").unwrap();
+
+        let roles: Vec<Role> = turns.iter().map(|t| t.role).collect();
+        assert_eq!(
+            roles,
+            [Role::User, Role::Assistant, Role::User, Role::Assistant]
+        );
+        assert_eq!(turns[0].content, "What is the tallest building in Paris?");
+        assert_eq!(turns[1].content, "The Eiffel Tower, at 330 m.");
+        assert!(turns[1].attachments.is_empty());
+        assert_eq!(
+            turns[2].content,
+            "Show me the Eiffel Tower and draw it at night"
+        );
+        let urls: Vec<Option<&str>> = turns[3]
+            .attachments
+            .iter()
+            .map(|a| a.url.as_deref())
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                Some("https://example.com/eiffel.jpg"),
+                Some("https://lh3.googleusercontent.com/gg/generated-1")
+            ]
+        );
+        assert!(turns[3].attachments.iter().all(|a| a.upload_id.is_empty()));
+    }
+
+    #[test]
+    fn unavailable_shares() {
+        let unavailable = |reply: &str| {
+            matches!(
+                parse_share_reply(reply, "gone"),
+                Err(Error::ShareUnavailable(id)) if id == "gone"
+            )
+        };
+        // Revoked shares fail the RPC; private ones answer without turns
+        assert!(unavailable(
+            ")]}'\n[[\"wrb.fr\",\"ujx1Bf\",null,null,null,[5]]]"
+        ));
+        assert!(unavailable(&crate::test_support::batch_reply(
+            READ_SHARE,
+            &json!([["gone"], null])
+        )));
+        assert!(unavailable(&crate::test_support::batch_reply(
+            READ_SHARE,
+            &json!([["gone"], []])
+        )));
+
+        let transient =
+            ")]}'\n[[\"wrb.fr\",\"ujx1Bf\",null,null,null,[3,null,[[\"type\",[1013]]]]]]";
+        assert!(matches!(
+            parse_share_reply(transient, "gone"),
+            Err(Error::Transient { code: 1013 })
+        ));
+        let error = parse_share_reply(")]}'\n[[\"di\",12]]", "gone").unwrap_err();
+        assert!(matches!(error, Error::Parse(_)), "{:?}", error);
+    }
+}
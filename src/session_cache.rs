@@ -0,0 +1,175 @@
+//! Init-page state shared between clients of the same account.
+
+use crate::error::Result;
+use crate::platform::Instant;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Caches the SNlM0e token per account so that building many clients for the
+/// same cookies fetches the init page once.
+///
+/// Entries are keyed by a hash of __Secure-1PSID and expire after the TTL.
+/// When several clients for the same account are built concurrently, one
+/// fetches the token and the others wait for its result. A client drops its
+/// account's entry when a request fails authentication, so the next build
+/// fetches a fresh token.
+///
+/// Only the token is cached: the `bl` build label is compiled into the
+/// client, and the model list is not discovered from the init page.
+///
+/// # Example
+/// ```no_run
+/// # async fn run() -> gemini_chat_api::Result<()> {
+/// use gemini_chat_api::{AsyncChatbot, SessionCache};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// let cache = Arc::new(SessionCache::new(Duration::from_secs(600)));
+/// for _ in 0..10 {
+///     let chatbot = AsyncChatbot::builder("psid", "psidts")
+///         .session_cache(Arc::clone(&cache))
+///         .build()
+///         .await?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct SessionCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Slot>>,
+}
+
+/// An account's entry; its lock is held while the token is fetched.
+type Slot = Arc<tokio::sync::Mutex<Option<CachedSession>>>;
+
+/// A cached token and when it was fetched.
+#[derive(Debug)]
+struct CachedSession {
+    snlm0e: String,
+    fetched_at: Instant,
+}
+
+impl SessionCache {
+    /// Creates an empty cache whose entries expire after `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Drops the cached token for the account with this __Secure-1PSID.
+    pub fn invalidate(&self, secure_1psid: &str) {
+        self.invalidate_key(&Self::key(secure_1psid));
+    }
+
+    /// Drops every cached token.
+    pub fn clear(&self) {
+        self.lock_entries().clear();
+    }
+
+    /// Returns the cache key of an account.
+    pub(crate) fn key(secure_1psid: &str) -> String {
+        use sha1::{Digest, Sha1};
+
+        Sha1::digest(secure_1psid.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Returns the cached token for `key`, or runs `fetch` and caches its
+    /// result.
+    ///
+    /// Callers for the same key are serialized, so concurrent misses run a
+    /// single fetch. Errors are not cached.
+    pub(crate) async fn get_or_fetch<F>(&self, key: &str, fetch: F) -> Result<String>
+    where
+        F: Future<Output = Result<String>>,
+    {
+        let slot = self.slot(key);
+        let mut entry = slot.lock().await;
+        if let Some(cached) = entry.as_ref() {
+            if cached.fetched_at.elapsed() < self.ttl {
+                return Ok(cached.snlm0e.clone());
+            }
+        }
+        let snlm0e = fetch.await?;
+        *entry = Some(CachedSession {
+            snlm0e: snlm0e.clone(),
+            fetched_at: Instant::now(),
+        });
+        Ok(snlm0e)
+    }
+
+    /// Replaces the cached token for `key` with a freshly fetched one.
+    pub(crate) async fn store(&self, key: &str, snlm0e: &str) {
+        let slot = self.slot(key);
+        *slot.lock().await = Some(CachedSession {
+            snlm0e: snlm0e.to_string(),
+            fetched_at: Instant::now(),
+        });
+    }
+
+    /// Drops the cached token for `key`.
+    pub(crate) fn invalidate_key(&self, key: &str) {
+        self.lock_entries().remove(key);
+    }
+
+    /// Returns the entry for `key`, creating an empty one.
+    fn slot(&self, key: &str) -> Slot {
+        Arc::clone(self.lock_entries().entry(key.to_string()).or_default())
+    }
+
+    fn lock_entries(&self) -> std::sync::MutexGuard<'_, HashMap<String, Slot>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn concurrent_misses_run_one_fetch() {
+        let cache = SessionCache::new(Duration::from_secs(600));
+        let key = SessionCache::key("psid");
+        let fetches = AtomicUsize::new(0);
+        let call = || {
+            cache.get_or_fetch(&key, async {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                // Stay pending so the other callers arrive during the fetch
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok("token".to_string())
+            })
+        };
+
+        let results = tokio::join!(
+            call(),
+            call(),
+            call(),
+            call(),
+            call(),
+            call(),
+            call(),
+            call(),
+            call(),
+            call()
+        );
+
+        let results = [
+            results.0, results.1, results.2, results.3, results.4, results.5, results.6, results.7,
+            results.8, results.9,
+        ];
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        for result in results {
+            assert_eq!(result.unwrap(), "token");
+        }
+    }
+}
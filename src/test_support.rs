@@ -0,0 +1,488 @@
+//! Local HTTP server and fixtures for tests of the request paths.
+//!
+//! [`MockServer`] speaks just enough HTTP/1.1 for reqwest: it records every
+//! request and answers with scripted responses, closing the connection after
+//! each one. Clients built with [`builder`] send all endpoints to it.
+
+use crate::client::{AsyncChatbot, AsyncChatbotBuilder};
+
+use serde_json::{json, Value};
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// __Secure-1PSID value accepted by cookie validation.
+pub(crate) const TEST_PSID: &str = "g.a000test-secure-1psid-value";
+
+/// __Secure-1PSIDTS value accepted by cookie validation.
+pub(crate) const TEST_PSIDTS: &str = "sidts-test-secure-1psidts-value";
+
+/// SNlM0e token the test clients start with.
+pub(crate) const TEST_SNLM0E: &str = "test-snlm0e-token";
+
+/// SAPISID cookie for tests of the `SAPISIDHASH` authorization.
+pub(crate) const TEST_SAPISID: &str = "test-sapisid/AbCdEfGhIjKlMnOp";
+
+/// Path of the Generate endpoint.
+pub(crate) const GENERATE_PATH: &str =
+    "/_/BardChatUi/data/assistant.lamda.BardFrontendService/StreamGenerate";
+
+/// Path of the batchexecute endpoint.
+pub(crate) const BATCH_PATH: &str = "/_/BardChatUi/data/batchexecute";
+
+/// Encodes `issued_at` the way __Secure-1PSIDTS does: a bytes field
+/// followed by the issue time as a varint field.
+pub(crate) fn psidts_at(issued_at: u64) -> String {
+    use base64::Engine;
+
+    let mut bytes = vec![0x0a, 0x03, b'a', b'b', b'c', 0x10];
+    let mut rest = issued_at;
+    while rest >= 0x80 {
+        bytes.push((rest as u8 & 0x7f) | 0x80);
+        rest >>= 7;
+    }
+    bytes.push(rest as u8);
+    format!(
+        "sidts-{}",
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    )
+}
+
+/// A request received by a [`MockServer`].
+#[derive(Debug, Clone)]
+pub(crate) struct RecordedRequest {
+    pub(crate) method: String,
+    /// Path and query, as sent.
+    pub(crate) target: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    /// The path without the query.
+    pub(crate) fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// Decoded query parameters, in order.
+    pub(crate) fn query(&self) -> Vec<(String, String)> {
+        let query = self.target.split_once('?').map_or("", |(_, query)| query);
+        url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect()
+    }
+
+    /// Value of a header, matched case-insensitively.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Decoded form fields of a URL-encoded body, in order.
+    pub(crate) fn form(&self) -> Vec<(String, String)> {
+        url::form_urlencoded::parse(&self.body)
+            .into_owned()
+            .collect()
+    }
+
+    /// Value of a form field.
+    pub(crate) fn form_value(&self, name: &str) -> Option<String> {
+        self.form()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// A scripted response.
+#[derive(Debug, Clone)]
+pub(crate) struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Body parts; more than one is sent chunked, with a pause between parts.
+    chunks: Vec<Vec<u8>>,
+}
+
+impl MockResponse {
+    /// A response with a complete body.
+    pub(crate) fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            chunks: vec![body.into()],
+        }
+    }
+
+    /// A `200 OK` response.
+    pub(crate) fn ok(body: impl Into<Vec<u8>>) -> Self {
+        Self::new(200, body)
+    }
+
+    /// A 200 response sent in `chunks`, with a pause after each.
+    pub(crate) fn chunked<S: Into<Vec<u8>>>(chunks: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            chunks: chunks.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// A redirect to `location`.
+    pub(crate) fn redirect(status: u16, location: &str) -> Self {
+        Self::new(status, "").header("location", location)
+    }
+
+    /// Adds a response header.
+    pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+type Handler = Arc<dyn Fn(&RecordedRequest) -> MockResponse + Send + Sync>;
+
+/// An HTTP server on a local port, stopped when dropped.
+pub(crate) struct MockServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts a server answering every request with `handler`.
+    pub(crate) async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&RecordedRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Handler = Arc::new(handler);
+        let recorded = Arc::clone(&requests);
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = Arc::clone(&handler);
+                let recorded = Arc::clone(&recorded);
+                tokio::spawn(async move {
+                    let _ = serve(stream, handler, recorded).await;
+                });
+            }
+        });
+        Self {
+            addr,
+            requests,
+            task,
+        }
+    }
+
+    /// Starts a server answering by path prefix.
+    ///
+    /// Each route answers with its responses in order and repeats the last
+    /// one when they run out. Requests matching no route get a 404.
+    pub(crate) async fn routes(routes: Vec<(&'static str, Vec<MockResponse>)>) -> Self {
+        let routes: Mutex<Vec<(&str, VecDeque<MockResponse>)>> = Mutex::new(
+            routes
+                .into_iter()
+                .map(|(prefix, responses)| (prefix, responses.into()))
+                .collect(),
+        );
+        Self::start(move |request| {
+            let mut routes = routes.lock().unwrap();
+            let Some((_, responses)) = routes
+                .iter_mut()
+                .find(|(prefix, _)| request.path().starts_with(prefix))
+            else {
+                return MockResponse::new(404, "no route");
+            };
+            if responses.len() > 1 {
+                responses.pop_front().unwrap()
+            } else {
+                responses.front().cloned().unwrap()
+            }
+        })
+        .await
+    }
+
+    /// The server's base URL, e.g. `http://127.0.0.1:4000`.
+    pub(crate) fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Every request received so far, in order.
+    pub(crate) fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The requests whose path starts with `prefix`, in order.
+    pub(crate) fn requests_to(&self, prefix: &str) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.path().starts_with(prefix))
+            .collect()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Reads one request from `stream`, records it and writes the response.
+async fn serve(
+    stream: TcpStream,
+    handler: Handler,
+    recorded: Arc<Mutex<Vec<RecordedRequest>>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let mut body = Vec::new();
+    if header("transfer-encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked")) {
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await?;
+            let size = usize::from_str_radix(line.trim(), 16).unwrap_or(0);
+            if size == 0 {
+                line.clear();
+                reader.read_line(&mut line).await?;
+                break;
+            }
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).await?;
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = header("content-length").and_then(|v| v.parse().ok()) {
+        body.resize(length, 0);
+        reader.read_exact(&mut body).await?;
+    }
+
+    let request = RecordedRequest {
+        method,
+        target,
+        headers,
+        body,
+    };
+    recorded.lock().unwrap().push(request.clone());
+    let response = handler(&request);
+
+    let mut stream = reader.into_inner();
+    let mut head = format!("HTTP/1.1 {} Mock\r\nconnection: close\r\n", response.status);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    let head_only = request.method == "HEAD";
+    if let [body] = response.chunks.as_slice() {
+        head.push_str(&format!("content-length: {}\r\n\r\n", body.len()));
+        stream.write_all(head.as_bytes()).await?;
+        if !head_only {
+            stream.write_all(body).await?;
+        }
+    } else {
+        head.push_str("transfer-encoding: chunked\r\n\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        for chunk in &response.chunks {
+            stream
+                .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                .await?;
+            stream.write_all(chunk).await?;
+            stream.write_all(b"\r\n").await?;
+            stream.flush().await?;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        stream.write_all(b"0\r\n\r\n").await?;
+    }
+    stream.flush().await?;
+    stream.shutdown().await
+}
+
+/// A builder for a client that sends every request to `server`.
+///
+/// The client starts with [`TEST_SNLM0E`], so building it makes no request,
+/// and its request IDs come from a fixed seed.
+pub(crate) fn builder(server: &MockServer) -> AsyncChatbotBuilder {
+    AsyncChatbot::builder(TEST_PSID, TEST_PSIDTS)
+        .with_snlm0e(TEST_SNLM0E.to_string())
+        .base_url(&server.url())
+        .rng_seed(7)
+        .proxy_from_env(false)
+}
+
+/// A client that sends every request to `server`; see [`builder`].
+pub(crate) async fn client(server: &MockServer) -> AsyncChatbot {
+    builder(server).build().await.unwrap()
+}
+
+/// A StreamGenerate reply with one frame carrying `choices` as `(id, text)`.
+pub(crate) fn generate_reply(
+    conversation_id: &str,
+    response_id: &str,
+    choices: &[(&str, &str)],
+) -> String {
+    let candidates: Vec<Value> = choices
+        .iter()
+        .map(|(id, text)| json!([id, [text]]))
+        .collect();
+    let body = json!([null, [conversation_id, response_id], null, null, candidates]);
+    generate_frames(&[body])
+}
+
+/// A StreamGenerate reply with one frame per response body.
+pub(crate) fn generate_frames(bodies: &[Value]) -> String {
+    let mut reply = ")]}'\n".to_string();
+    for body in bodies {
+        let frame = json!([["wrb.fr", null, body.to_string()]]).to_string();
+        reply.push_str(&format!("\n{}\n{}\n", frame.len(), frame));
+    }
+    reply
+}
+
+/// A batchexecute reply carrying `payload` for `rpc_id`.
+pub(crate) fn batch_reply(rpc_id: &str, payload: &Value) -> String {
+    let frame = json!([
+        [
+            "wrb.fr",
+            rpc_id,
+            payload.to_string(),
+            null,
+            null,
+            null,
+            "generic"
+        ],
+        ["di", 87],
+        ["af.httprm", 86, "-1234567890123456789", 3]
+    ])
+    .to_string();
+    format!(")]}}'\n\n{}\n{}\n", frame.len(), frame)
+}
+
+/// Compares `actual` with the golden file `testdata/golden/<name>`.
+///
+/// With `UPDATE_GOLDEN=1` in the environment, the file is rewritten instead.
+pub(crate) fn assert_golden(name: &str, actual: &str) {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata/golden")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e));
+    assert_eq!(
+        actual, expected,
+        "request differs from {}; rerun with UPDATE_GOLDEN=1 if the change is intended",
+        name
+    );
+}
+
+/// Renders a request for golden comparison: the request line, then the
+/// query, form fields and headers, one per line.
+///
+/// `host` and `content-length` vary between runs and are left out.
+pub(crate) fn render_request(request: &RecordedRequest) -> String {
+    let mut lines = vec![format!("{} {}", request.method, request.path())];
+    for (name, value) in request.query() {
+        lines.push(format!("query {}={}", name, value));
+    }
+    for (name, value) in request.form() {
+        lines.push(format!("form {}={}", name, value));
+    }
+    let mut headers: Vec<&(String, String)> = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name != "host" && name != "content-length")
+        .collect();
+    headers.sort();
+    for (name, value) in headers {
+        lines.push(format!("header {}: {}", name, value));
+    }
+    lines.join("\n") + "\n"
+}
+
+/// The message struct a Generate request carried in `f.req`.
+pub(crate) fn sent_message(request: &RecordedRequest) -> Value {
+    let freq: Value = serde_json::from_str(&request.form_value("f.req").unwrap()).unwrap();
+    serde_json::from_str(freq[1].as_str().unwrap()).unwrap()
+}
+
+/// The prompt text a Generate request carried.
+pub(crate) fn sent_prompt(request: &RecordedRequest) -> String {
+    sent_message(request)[0][0].as_str().unwrap().to_string()
+}
+
+/// The conversation, response and choice IDs a Generate request threaded from.
+pub(crate) fn sent_thread(request: &RecordedRequest) -> Value {
+    sent_message(request)[2].clone()
+}
+
+/// A path in a fresh temporary directory, removed when the guard is dropped.
+pub(crate) struct TempPath {
+    dir: std::path::PathBuf,
+    path: std::path::PathBuf,
+}
+
+impl TempPath {
+    /// Creates a directory unique to this call, holding the file `name`.
+    pub(crate) fn new(name: &str) -> Self {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "gemini-chat-api-test-{}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        Self { dir, path }
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// An init page carrying `snlm0e`.
+pub(crate) fn init_page(snlm0e: &str) -> String {
+    format!(
+        r#"<html><script>window.WIZ_global_data = {{"SNlM0e":"{}","cfb2h":"boq"}};</script></html>"#,
+        snlm0e
+    )
+}
@@ -0,0 +1,161 @@
+//! Usage accounting hooks for enforcing application-level quotas.
+
+use crate::enums::Model;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Usage of a single completed operation.
+#[derive(Debug, Clone)]
+pub struct UsageRecord {
+    /// Conversation the operation belongs to.
+    pub conversation_id: String,
+    /// Characters sent in the prompt.
+    pub prompt_chars: usize,
+    /// Characters received in the response content.
+    pub response_chars: usize,
+    /// Bytes of uploaded attachments.
+    pub attachments_bytes: usize,
+    /// Wall-clock duration of the whole operation, including uploads.
+    pub duration: Duration,
+    /// Model the request was sent to.
+    pub model: Model,
+}
+
+/// Receives a [`UsageRecord`] after each successful operation.
+///
+/// Register with [`AsyncChatbotBuilder::usage_tracker`](crate::client::AsyncChatbotBuilder::usage_tracker).
+/// Nothing is recorded when no tracker is set.
+pub trait UsageTracker: Send + Sync {
+    /// Records a completed operation.
+    fn record(&self, record: &UsageRecord);
+}
+
+/// Accumulated usage totals.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsageTotals {
+    /// Number of recorded operations.
+    pub requests: usize,
+    /// Total prompt characters.
+    pub prompt_chars: usize,
+    /// Total response characters.
+    pub response_chars: usize,
+    /// Total attachment bytes.
+    pub attachments_bytes: usize,
+    /// Total operation time.
+    pub duration: Duration,
+}
+
+impl UsageTotals {
+    fn add(&mut self, record: &UsageRecord) {
+        self.requests += 1;
+        self.prompt_chars += record.prompt_chars;
+        self.response_chars += record.response_chars;
+        self.attachments_bytes += record.attachments_bytes;
+        self.duration += record.duration;
+    }
+}
+
+/// Snapshot of usage recorded by an [`InMemoryUsageTracker`].
+#[derive(Debug, Clone, Default)]
+pub struct UsageSummary {
+    /// Totals across all conversations.
+    pub totals: UsageTotals,
+    /// Totals keyed by conversation ID.
+    pub per_conversation: HashMap<String, UsageTotals>,
+}
+
+/// Built-in tracker keeping totals in memory.
+#[derive(Debug, Default)]
+pub struct InMemoryUsageTracker {
+    summary: Mutex<UsageSummary>,
+}
+
+impl InMemoryUsageTracker {
+    /// Creates an empty tracker.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a snapshot of the recorded usage.
+    pub fn usage(&self) -> UsageSummary {
+        self.summary
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+impl UsageTracker for InMemoryUsageTracker {
+    fn record(&self, record: &UsageRecord) {
+        let mut summary = self.summary.lock().unwrap_or_else(|e| e.into_inner());
+        summary.totals.add(record);
+        summary
+            .per_conversation
+            .entry(record.conversation_id.clone())
+            .or_default()
+            .add(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{builder, generate_reply, MockResponse, MockServer, GENERATE_PATH};
+
+    use std::sync::Arc;
+
+    /// Totals without the duration, which depends on timing.
+    fn counts(totals: &UsageTotals) -> (usize, usize, usize, usize) {
+        (
+            totals.requests,
+            totals.prompt_chars,
+            totals.response_chars,
+            totals.attachments_bytes,
+        )
+    }
+
+    #[tokio::test]
+    async fn scripted_conversation_produces_exact_totals() {
+        let server = MockServer::routes(vec![
+            (
+                GENERATE_PATH,
+                vec![
+                    MockResponse::ok(generate_reply("c_1", "r_1", &[("rc_1", "Reply one")])),
+                    MockResponse::ok(generate_reply("c_1", "r_2", &[("rc_2", "Reply two!")])),
+                    MockResponse::new(500, "server error"),
+                    MockResponse::ok(generate_reply("c_2", "r_3", &[("rc_3", "Ok")])),
+                ],
+            ),
+            (
+                "/upload",
+                vec![MockResponse::ok("/contrib_service/ttl_1d/img")],
+            ),
+        ])
+        .await;
+        let tracker = Arc::new(InMemoryUsageTracker::new());
+        let mut chatbot = builder(&server)
+            .usage_tracker(tracker.clone())
+            .build()
+            .await
+            .unwrap();
+
+        chatbot.ask("Hello", None).await.unwrap();
+        chatbot.ask("Describe", Some(b"GIF89a")).await.unwrap();
+        // Failed requests are not recorded
+        assert!(chatbot.ask("Lost", None).await.is_err());
+        chatbot.reset();
+        chatbot.ask("Bye", None).await.unwrap();
+
+        let usage = tracker.usage();
+        assert_eq!(counts(&usage.totals), (3, 16, 21, 6));
+        assert_eq!(usage.per_conversation.len(), 2);
+        assert_eq!(counts(&usage.per_conversation["c_1"]), (2, 13, 19, 6));
+        assert_eq!(counts(&usage.per_conversation["c_2"]), (1, 3, 2, 0));
+        assert_eq!(
+            usage.totals.duration,
+            usage.per_conversation["c_1"].duration + usage.per_conversation["c_2"].duration
+        );
+    }
+}